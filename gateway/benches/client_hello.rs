@@ -0,0 +1,78 @@
+//! Time to parse each ClientHello in the corpus. Run with `cargo bench -p
+//! holo-router-gateway --bench client_hello`.
+//!
+//! Corpus is a directory of raw handshake messages (without record headers),
+//! see its README.md for how to capture more. Unit tests check that
+//! all of them parse, and that truncations and mutations of them don't break
+//! the parser.
+
+#[allow(dead_code)]
+#[path = "../src/client_hello.rs"]
+mod client_hello;
+
+use failure::*;
+
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use client_hello::ClientHello;
+
+const CORPUS_PATH: &str = "benches/corpus/client_hello";
+const ITERATIONS: usize = 100_000;
+
+fn describe(client_hello: &ClientHello) -> String {
+    let alpn: Vec<_> = client_hello
+        .alpn_protocols()
+        .map(String::from_utf8_lossy)
+        .collect();
+    let versions: Vec<_> = client_hello
+        .supported_versions()
+        .map(|version| format!("{:#06x}", version))
+        .collect();
+
+    format!(
+        "sni = {:?}, alpn = {:?}, versions = {:?}, extensions = {}",
+        client_hello.server_name,
+        alpn,
+        versions,
+        client_hello.extensions().count()
+    )
+}
+
+fn bench(name: &str, sample: &[u8]) -> Fallible<()> {
+    let client_hello = ClientHello::parse(sample).map_err(|e| format_err!("{}: {}", name, e))?;
+    println!("{}: {}", name, describe(&client_hello));
+
+    let start = Instant::now();
+
+    for _ in 0..ITERATIONS {
+        ClientHello::parse(sample).unwrap();
+    }
+
+    println!(
+        "{}: {:?} per parse",
+        name,
+        start.elapsed() / ITERATIONS as u32
+    );
+
+    Ok(())
+}
+
+fn main() -> Fallible<()> {
+    let mut paths: Vec<_> = fs::read_dir(Path::new(CORPUS_PATH))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| {
+        path.extension()
+            .map_or(false, |extension| extension == "bin")
+    });
+    paths.sort();
+
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        bench(&name, &fs::read(&path)?)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Throughput of splice through a local echo upstream, with and without
+//! zero-copy. Run with `cargo bench -p holo-router-gateway`.
+
+#[allow(dead_code)]
+#[path = "../src/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../src/hostname.rs"]
+mod hostname;
+#[allow(dead_code)]
+#[path = "../src/ip.rs"]
+mod ip;
+#[allow(dead_code)]
+#[path = "../src/peek.rs"]
+mod peek;
+#[allow(dead_code)]
+#[path = "../src/proxy_protocol.rs"]
+mod proxy_protocol;
+#[allow(dead_code)]
+#[path = "../src/rejection.rs"]
+mod rejection;
+#[allow(dead_code)]
+#[path = "../src/splice.rs"]
+mod splice;
+#[cfg(target_os = "linux")]
+#[path = "../src/zerocopy.rs"]
+mod zerocopy;
+
+use failure::*;
+use futures::future;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use config::SpliceConfig;
+
+const PAYLOAD_SIZE: usize = 1 << 30;
+const CHUNK_SIZE: usize = 1 << 16;
+
+async fn echo(mut listener: TcpListener) -> Fallible<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+    }
+}
+
+async fn proxy(
+    mut listener: TcpListener,
+    upstream: SocketAddr,
+    config: Arc<SpliceConfig>,
+) -> Fallible<()> {
+    loop {
+        let (inbound, _) = listener.accept().await?;
+        let outbound = TcpStream::connect(upstream).await?;
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            let counters = splice::Counters::default();
+            let _ = splice::splice(inbound, outbound, &config, &counters, future::pending()).await;
+        });
+    }
+}
+
+async fn run(zero_copy: bool) -> Fallible<Duration> {
+    let echo_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let echo_addr = echo_listener.local_addr()?;
+    tokio::spawn(echo(echo_listener));
+
+    let config = Arc::new(SpliceConfig {
+        zero_copy,
+        ..Default::default()
+    });
+
+    let proxy_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy_listener.local_addr()?;
+    tokio::spawn(proxy(proxy_listener, echo_addr, config));
+
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    let (mut reader, mut writer) = client.split();
+    let start = Instant::now();
+
+    let write = async {
+        let chunk = vec![0; CHUNK_SIZE];
+
+        for _ in 0..PAYLOAD_SIZE / CHUNK_SIZE {
+            writer.write_all(&chunk).await?;
+        }
+
+        writer.shutdown().await
+    };
+
+    let read = async {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut total = 0;
+
+        while total < PAYLOAD_SIZE {
+            match reader.read(&mut buf).await? {
+                0 => break,
+                n => total += n,
+            }
+        }
+
+        Ok::<_, io::Error>(total)
+    };
+
+    let (_, total) = future::try_join(write, read).await?;
+    let elapsed = start.elapsed();
+
+    if total != PAYLOAD_SIZE {
+        bail!("Echoed {} bytes out of {}", total, PAYLOAD_SIZE);
+    }
+
+    Ok(elapsed)
+}
+
+#[tokio::main]
+async fn main() -> Fallible<()> {
+    for &zero_copy in &[false, true] {
+        if zero_copy {
+            if let Err(e) = splice::check_zero_copy() {
+                println!("zero_copy = true: skipped, {}", e);
+                continue;
+            }
+        }
+
+        let elapsed = run(zero_copy).await?;
+        let throughput = PAYLOAD_SIZE as f64 / elapsed.as_secs_f64() / f64::from(1 << 20);
+
+        println!(
+            "zero_copy = {}: {:.0} MiB/s ({:?})",
+            zero_copy, throughput, elapsed
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use failure::*;
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::ip;
+use crate::reload::Load;
+
+/// Inbound CIDR allow and deny lists. Deny rules take precedence, and if
+/// allow list is not empty, only addresses that match it are allowed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+/// Rule that caused a denial, for logs.
+#[derive(Debug)]
+pub enum Denial {
+    Deny(IpNet),
+    NotAllowed,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::Deny(net) => write!(f, "deny {}", net),
+            Denial::NotAllowed => write!(f, "not in allow list"),
+        }
+    }
+}
+
+impl Acl {
+    pub fn check(&self, ip: IpAddr) -> Result<(), Denial> {
+        let ip = ip::canonical(ip);
+
+        if let Some(net) = self.deny.iter().find(|net| net.contains(&ip)) {
+            return Err(Denial::Deny(*net));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&ip)) {
+            return Err(Denial::NotAllowed);
+        }
+
+        Ok(())
+    }
+}
+
+impl Load for Acl {
+    fn load(path: &Path) -> Fallible<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
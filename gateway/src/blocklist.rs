@@ -0,0 +1,52 @@
+use failure::*;
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::hostname::AgentHostname;
+use crate::reload::Load;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlocklistFile {
+    agents: Vec<String>,
+    hostnames: Vec<String>,
+    notice_upstream: Option<SocketAddr>,
+}
+
+/// Agent ids and hostnames taken down for abuse. Blocking agent id covers
+/// every hostname of the agent, even if it gets re-registered.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    agents: HashSet<String>,
+    hostnames: HashSet<String>,
+    /// Upstream serving takedown notice for blocked hostnames. Blocked
+    /// connections get a TLS alert if not set.
+    pub notice_upstream: Option<SocketAddr>,
+}
+
+impl Blocklist {
+    pub fn is_blocked(&self, hostname: &str, agent: Option<&AgentHostname>) -> bool {
+        self.hostnames.contains(&hostname.to_ascii_lowercase())
+            || agent.map_or(false, |agent| self.agents.contains(agent.agent_id()))
+    }
+}
+
+impl Load for Blocklist {
+    fn load(path: &Path) -> Fallible<Self> {
+        let file: BlocklistFile = toml::from_str(&fs::read_to_string(path)?)?;
+
+        Ok(Blocklist {
+            agents: file.agents.iter().map(|x| x.to_ascii_lowercase()).collect(),
+            hostnames: file
+                .hostnames
+                .iter()
+                .map(|x| x.to_ascii_lowercase())
+                .collect(),
+            notice_upstream: file.notice_upstream,
+        })
+    }
+}
@@ -0,0 +1,488 @@
+//! ClientHello parser that borrows from raw handshake bytes and never
+//! allocates. Only extensions that gateway dispatches by are interpreted,
+//! the rest are checked for framing and exposed as is.
+//!
+//! See: https://tools.ietf.org/html/rfc8446#section-4.1.2
+
+use failure::*;
+
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const RANDOM_LENGTH: usize = 32;
+const MAX_SESSION_ID_LENGTH: usize = 32;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+pub const EXTENSION_SERVER_NAME: u16 = 0;
+pub const EXTENSION_ALPN: u16 = 16;
+pub const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct Malformed(pub &'static str);
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, length: usize, what: &'static str) -> Result<&'a [u8], Malformed> {
+        if self.buf.len() < length {
+            return Err(Malformed(what));
+        }
+
+        let (head, tail) = self.buf.split_at(length);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self, what: &'static str) -> Result<u8, Malformed> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &'static str) -> Result<u16, Malformed> {
+        let x = self.take(2, what)?;
+        Ok(u16::from(x[0]) << 8 | u16::from(x[1]))
+    }
+
+    fn u24(&mut self, what: &'static str) -> Result<usize, Malformed> {
+        let x = self.take(3, what)?;
+        Ok(usize::from(x[0]) << 16 | usize::from(x[1]) << 8 | usize::from(x[2]))
+    }
+
+    /// Reads vector with 8-bit length prefix.
+    fn vec8(&mut self, what: &'static str) -> Result<&'a [u8], Malformed> {
+        let length = self.u8(what)?;
+        self.take(usize::from(length), what)
+    }
+
+    /// Reads vector with 16-bit length prefix.
+    fn vec16(&mut self, what: &'static str) -> Result<&'a [u8], Malformed> {
+        let length = self.u16(what)?;
+        self.take(usize::from(length), what)
+    }
+
+    fn finish(&self, what: &'static str) -> Result<(), Malformed> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(Malformed(what))
+        }
+    }
+}
+
+/// Extension that is left uninterpreted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extension<'a> {
+    pub typ: u16,
+    pub data: &'a [u8],
+}
+
+/// Iterator over extensions, in the order client sent them.
+pub struct Extensions<'a>(Reader<'a>);
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Extension<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Framing has been checked by parse, so errors only mean the end:
+        let typ = self.0.u16("").ok()?;
+        let data = self.0.vec16("").ok()?;
+
+        Some(Extension { typ, data })
+    }
+}
+
+/// Fields gateway doesn't dispatch by (legacy version, random, session id,
+/// cipher suites and compression methods) are checked, but not kept.
+#[derive(Clone, Debug, Default)]
+pub struct ClientHello<'a> {
+    /// Host name from SNI extension
+    pub server_name: Option<&'a str>,
+    extensions: &'a [u8],
+    alpn: &'a [u8],
+    supported_versions: &'a [u8],
+}
+
+fn is_host_name(name: &[u8]) -> bool {
+    !name.is_empty()
+        && !name.ends_with(b".")
+        && name
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+}
+
+fn parse_server_name(data: &[u8]) -> Result<&str, Malformed> {
+    let mut rd = Reader::new(data);
+    let mut list = Reader::new(rd.vec16("failed to read server name list")?);
+    rd.finish("trailing data after server name list")?;
+
+    let mut host_name = None;
+
+    while !list.is_empty() {
+        if list.u8("failed to read server name type")? != NAME_TYPE_HOST_NAME {
+            return Err(Malformed("SNI payload uses unknown format"));
+        }
+
+        let name = list.vec16("failed to read host name")?;
+
+        if host_name.replace(name).is_some() {
+            return Err(Malformed("duplicate host name in SNI"));
+        }
+    }
+
+    match host_name {
+        Some(name) if is_host_name(name) => {
+            std::str::from_utf8(name).map_err(|_| Malformed("host name is not ASCII"))
+        }
+        Some(_) => Err(Malformed("invalid host name in SNI")),
+        None => Err(Malformed("server name list is empty")),
+    }
+}
+
+fn parse_alpn(data: &[u8]) -> Result<&[u8], Malformed> {
+    let mut rd = Reader::new(data);
+    let list = rd.vec16("failed to read ALPN protocol list")?;
+    rd.finish("trailing data after ALPN protocol list")?;
+
+    if list.is_empty() {
+        return Err(Malformed("ALPN protocol list is empty"));
+    }
+
+    let mut protocols = Reader::new(list);
+
+    while !protocols.is_empty() {
+        if protocols.vec8("failed to read ALPN protocol")?.is_empty() {
+            return Err(Malformed("ALPN protocol is empty"));
+        }
+    }
+
+    Ok(list)
+}
+
+fn parse_supported_versions(data: &[u8]) -> Result<&[u8], Malformed> {
+    let mut rd = Reader::new(data);
+    let list = rd.vec8("failed to read supported versions")?;
+    rd.finish("trailing data after supported versions")?;
+
+    if list.is_empty() || list.len() % 2 != 0 {
+        return Err(Malformed("supported versions list has invalid length"));
+    }
+
+    Ok(list)
+}
+
+impl<'a> ClientHello<'a> {
+    /// Parses complete handshake message, including its 4-byte header.
+    pub fn parse(handshake: &'a [u8]) -> Result<Self, Malformed> {
+        let mut rd = Reader::new(handshake);
+
+        if rd.u8("failed to read handshake type")? != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(Malformed("handshake payload is not ClientHello"));
+        }
+
+        let length = rd.u24("failed to read handshake length")?;
+        let mut rd = Reader::new(rd.take(length, "handshake is truncated")?);
+
+        rd.u16("failed to read protocol version")?;
+        rd.take(RANDOM_LENGTH, "failed to read random")?;
+
+        if rd.vec8("failed to read session id")?.len() > MAX_SESSION_ID_LENGTH {
+            return Err(Malformed("session id is too long"));
+        }
+
+        let cipher_suites = rd.vec16("failed to read cipher suites")?;
+
+        if cipher_suites.is_empty() || cipher_suites.len() % 2 != 0 {
+            return Err(Malformed("cipher suites list has invalid length"));
+        }
+
+        if rd.vec8("failed to read compression methods")?.is_empty() {
+            return Err(Malformed("compression methods list is empty"));
+        }
+
+        let mut client_hello = ClientHello::default();
+
+        // Extensions may be omitted altogether by pre-TLS 1.2 clients:
+        if rd.is_empty() {
+            return Ok(client_hello);
+        }
+
+        client_hello.extensions = rd.vec16("failed to read extensions")?;
+        rd.finish("trailing data after extensions")?;
+
+        let mut extensions = Reader::new(client_hello.extensions);
+
+        while !extensions.is_empty() {
+            let typ = extensions.u16("failed to read extension type")?;
+            let data = extensions.vec16("failed to read extension data")?;
+
+            match typ {
+                EXTENSION_SERVER_NAME => {
+                    let name = parse_server_name(data)?;
+
+                    if client_hello.server_name.replace(name).is_some() {
+                        return Err(Malformed("duplicate SNI extension"));
+                    }
+                }
+                EXTENSION_ALPN => {
+                    if !client_hello.alpn.is_empty() {
+                        return Err(Malformed("duplicate ALPN extension"));
+                    }
+
+                    client_hello.alpn = parse_alpn(data)?;
+                }
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    if !client_hello.supported_versions.is_empty() {
+                        return Err(Malformed("duplicate supported versions extension"));
+                    }
+
+                    client_hello.supported_versions = parse_supported_versions(data)?;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(client_hello)
+    }
+
+    pub fn extensions(&self) -> Extensions<'a> {
+        Extensions(Reader::new(self.extensions))
+    }
+
+    /// Protocols from ALPN extension, in client preference order.
+    pub fn alpn_protocols(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let mut rd = Reader::new(self.alpn);
+        std::iter::from_fn(move || rd.vec8("").ok())
+    }
+
+    /// Versions from supported versions extension (TLS 1.3 and later
+    /// clients), in client preference order.
+    pub fn supported_versions(&self) -> impl Iterator<Item = u16> + 'a {
+        let mut rd = Reader::new(self.supported_versions);
+        std::iter::from_fn(move || rd.u16("").ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Raw handshake messages (without record headers), see README.md there
+    /// for where each one comes from.
+    const CORPUS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/corpus/client_hello");
+    const MUTATIONS: usize = 10_000;
+
+    const EXTENSION_KEY_SHARE: u16 = 51;
+    const EXTENSION_ECH: u16 = 0xfe0d;
+    const GROUP_X25519: u16 = 0x001d;
+    const GROUP_X25519_MLKEM768: u16 = 0x11ec;
+    const GREASE: [u16; 4] = [0x0a0a, 0x3a3a, 0x7a7a, 0xdada];
+
+    /// Deterministic xorshift, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn corpus() -> Vec<(String, Vec<u8>)> {
+        let mut paths: Vec<_> = fs::read_dir(CORPUS_PATH)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "bin")
+            })
+            .collect();
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, fs::read(&path).unwrap())
+            })
+            .collect()
+    }
+
+    fn vec8(data: &[u8]) -> Vec<u8> {
+        [&[data.len() as u8][..], data].concat()
+    }
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        [&(data.len() as u16).to_be_bytes()[..], data].concat()
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    fn extension(typ: u16, data: &[u8]) -> Vec<u8> {
+        [&typ.to_be_bytes()[..], &vec16(data)].concat()
+    }
+
+    /// Builds ClientHello with the features that set current browsers apart
+    /// from curl and openssl in the corpus: GREASE cipher suite, extensions,
+    /// versions and key share, X25519MLKEM768 key share and outer ECH.
+    fn browser_client_hello() -> Vec<u8> {
+        let server_name = [&[0][..], &vec16(b"example.holohost.net")].concat();
+        let alpn = [vec8(b"h2"), vec8(b"http/1.1")].concat();
+        let key_shares = [
+            u16s(&[GREASE[1]]),
+            vec16(&[0]),
+            u16s(&[GROUP_X25519_MLKEM768]),
+            vec16(&[0x11; 1216]),
+            u16s(&[GROUP_X25519]),
+            vec16(&[0x22; 32]),
+        ]
+        .concat();
+        let ech = [
+            &[0][..],
+            &u16s(&[1, 1]),
+            &[0x33],
+            &vec16(&[0x44; 32]),
+            &vec16(&[0x55; 208]),
+        ]
+        .concat();
+
+        let extensions = [
+            extension(GREASE[0], &[]),
+            extension(EXTENSION_SERVER_NAME, &vec16(&server_name)),
+            extension(EXTENSION_ALPN, &vec16(&alpn)),
+            extension(EXTENSION_KEY_SHARE, &vec16(&key_shares)),
+            extension(
+                EXTENSION_SUPPORTED_VERSIONS,
+                &vec8(&u16s(&[GREASE[2], 0x0304, 0x0303])),
+            ),
+            extension(EXTENSION_ECH, &ech),
+            extension(GREASE[3], &[0]),
+        ]
+        .concat();
+
+        let body = [
+            &u16s(&[0x0303])[..],
+            &[0x66; 32],
+            &vec8(&[0x77; 32]),
+            &vec16(&u16s(&[GREASE[0], 0x1301, 0x1302, 0x1303])),
+            &vec8(&[0]),
+            &vec16(&extensions),
+        ]
+        .concat();
+
+        [&[1][..], &(body.len() as u32).to_be_bytes()[1..], &body].concat()
+    }
+
+    fn is_grease(value: u16) -> bool {
+        value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+    }
+
+    /// Groups offered in key share extension.
+    fn key_share_groups(client_hello: &ClientHello) -> Vec<u16> {
+        let extension = client_hello
+            .extensions()
+            .find(|extension| extension.typ == EXTENSION_KEY_SHARE)
+            .unwrap();
+
+        let mut rd = Reader::new(extension.data);
+        let mut shares = Reader::new(rd.vec16("").unwrap());
+        let mut groups = Vec::new();
+
+        while !shares.is_empty() {
+            groups.push(shares.u16("").unwrap());
+            shares.vec16("").unwrap();
+        }
+
+        groups
+    }
+
+    #[test]
+    fn parses_corpus() {
+        for (name, sample) in corpus() {
+            let client_hello =
+                ClientHello::parse(&sample).unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            assert_eq!(
+                client_hello.server_name.is_some(),
+                !name.contains("no-sni"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn parses_browser_extensions() {
+        let client_hello = browser_client_hello();
+        let client_hello = ClientHello::parse(&client_hello).unwrap();
+        let versions: Vec<_> = client_hello.supported_versions().collect();
+        let alpn: Vec<_> = client_hello.alpn_protocols().collect();
+
+        assert_eq!(client_hello.server_name, Some("example.holohost.net"));
+        assert_eq!(versions, [GREASE[2], 0x0304, 0x0303]);
+        assert_eq!(alpn, [&b"h2"[..], b"http/1.1"]);
+        assert_eq!(
+            key_share_groups(&client_hello),
+            [GREASE[1], GROUP_X25519_MLKEM768, GROUP_X25519]
+        );
+        assert!(client_hello
+            .extensions()
+            .any(|extension| is_grease(extension.typ)));
+        assert!(client_hello
+            .extensions()
+            .any(|extension| extension.typ == EXTENSION_ECH));
+    }
+
+    #[test]
+    fn rejects_truncations() {
+        for (name, sample) in corpus() {
+            for length in 0..sample.len() {
+                assert!(
+                    ClientHello::parse(&sample[..length]).is_err(),
+                    "{}: truncation to {} bytes is accepted",
+                    name,
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn survives_mutations() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for (_, sample) in corpus() {
+            for _ in 0..MUTATIONS {
+                let mut mutated = sample.clone();
+
+                for _ in 0..=rng.next_u64() % 4 {
+                    let i = rng.next_u64() as usize % mutated.len();
+                    mutated[i] = rng.next_u64() as u8;
+                }
+
+                // Mutations of opaque bytes are accepted, so this only checks
+                // that parser doesn't panic and its iterators terminate:
+                if let Ok(client_hello) = ClientHello::parse(&mutated) {
+                    client_hello.alpn_protocols().count();
+                    client_hello.supported_versions().count();
+                    client_hello.extensions().count();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,619 @@
+use failure::*;
+use ipnet::IpNet;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::hostname::AgentHostname;
+use crate::rejection::Rejection;
+use crate::{ip, proxy_protocol};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "holo-router-gateway")]
+pub struct Opt {
+    /// Path to TOML config file
+    #[structopt(long, env = "HOLO_ROUTER_GATEWAY_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, can be specified several times
+    #[structopt(long, env = "HOLO_ROUTER_GATEWAY_LISTEN", use_delimiter = true)]
+    pub listen: Vec<SocketAddr>,
+    /// Hostname suffix allowed for dispatch, can be specified several times
+    #[structopt(long, env = "HOLO_ROUTER_GATEWAY_ALLOWED_SUFFIX", use_delimiter = true)]
+    pub allowed_suffix: Vec<String>,
+    /// Port to connect to on upstream hosts
+    #[structopt(long, env = "HOLO_ROUTER_GATEWAY_UPSTREAM_PORT")]
+    pub upstream_port: Option<u16>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Summarise per-hostname usage from traffic ledger
+    Usage {
+        /// Ledger path, defaults to one from config
+        #[structopt(long, parse(from_os_str))]
+        ledger: Option<PathBuf>,
+        /// Start of time range, in RFC 3339 format
+        #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        from: Option<SystemTime>,
+        /// End of time range, in RFC 3339 format
+        #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+        to: Option<SystemTime>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub allowed_suffixes: Vec<String>,
+    /// Allow app labels in front of agent id, as in `<app>.<agent-id><suffix>`
+    pub nested_labels: bool,
+    pub upstream_port: u16,
+    /// PROXY protocol version to send to upstream hosts, by hostname suffix
+    pub proxy_protocol: HashMap<String, proxy_protocol::Version>,
+    /// Networks of load balancers that are trusted to send PROXY protocol
+    /// header with original client address. Connections from these must
+    /// start with such header
+    pub trusted_proxies: Vec<IpNet>,
+    pub handshake: HandshakeConfig,
+    pub splice: SpliceConfig,
+    pub resolver: ResolverConfig,
+    pub connect: ConnectConfig,
+    /// Static route table, checked before DNS
+    pub routes: ReloadableFileConfig,
+    /// Inbound CIDR allow and deny lists
+    pub acl: ReloadableFileConfig,
+    /// Agent ids and hostnames taken down for abuse
+    pub blocklist: ReloadableFileConfig,
+    pub metrics: MetricsConfig,
+    pub ledger: LedgerConfig,
+    pub shutdown: ShutdownConfig,
+    pub http: HttpConfig,
+    pub quic: QuicConfig,
+    pub limits: LimitsConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandshakeConfig {
+    /// Max number of bytes (including TLS record headers) to peek while
+    /// reassembling ClientHello
+    pub max_length: usize,
+    /// Seconds from accept until the full ClientHello has to arrive
+    pub timeout: u64,
+    /// Max number of connections per source IP that are still in the
+    /// handshake phase
+    pub max_per_ip: usize,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            max_length: 16384,
+            timeout: 10,
+            max_per_ip: 32,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpliceConfig {
+    /// Seconds without traffic in either direction before connection is closed
+    pub idle_timeout: u64,
+    /// Seconds after which connection is closed regardless of traffic
+    pub max_lifetime: u64,
+    /// Move data with splice(2) instead of copying it through userspace
+    /// (Linux only, disabled at startup if splice(2) doesn't work)
+    pub zero_copy: bool,
+}
+
+impl Default for SpliceConfig {
+    fn default() -> Self {
+        SpliceConfig {
+            idle_timeout: 300,
+            max_lifetime: 86400,
+            zero_copy: false,
+        }
+    }
+}
+
+impl SpliceConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.max_lifetime)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// DNS-over-HTTPS endpoint, such as Registry `/v1/dns-query`. If not set,
+    /// system resolver is used
+    pub doh_url: Option<String>,
+    /// Also query AAAA records
+    pub ipv6: bool,
+    /// Seconds to wait for DoH response
+    pub timeout: u64,
+    /// Upper bound on TTL of cached answers, in seconds
+    pub max_ttl: u32,
+    /// TTL of negative answers without SOA record, in seconds
+    pub negative_ttl: u32,
+    /// Max number of cached hostnames
+    pub cache_size: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            doh_url: None,
+            ipv6: false,
+            timeout: 5,
+            max_ttl: 300,
+            negative_ttl: 10,
+            cache_size: 65536,
+        }
+    }
+}
+
+impl ResolverConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// File that is reloaded at runtime, on SIGHUP or modification.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReloadableFileConfig {
+    pub path: Option<PathBuf>,
+    /// Seconds between checks of file modification time
+    pub reload_interval: u64,
+}
+
+impl Default for ReloadableFileConfig {
+    fn default() -> Self {
+        ReloadableFileConfig {
+            path: None,
+            reload_interval: 5,
+        }
+    }
+}
+
+impl ReloadableFileConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Admin listener address for Prometheus `GET /metrics` endpoint
+    pub listen: Option<SocketAddr>,
+    /// Label byte counters with destination hostname
+    pub hostname_labels: bool,
+    /// Max number of distinct hostname label values, the rest are
+    /// reported as `_other`
+    pub max_hostnames: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            listen: None,
+            hostname_labels: false,
+            max_hostnames: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LedgerConfig {
+    /// JSON lines file with per-hostname usage, accounting is disabled if
+    /// not set
+    pub path: Option<PathBuf>,
+    /// Seconds between ledger flushes
+    pub flush_interval: u64,
+    /// Size in bytes after which ledger is rotated
+    pub max_size: u64,
+    /// Max number of rotated ledger files to keep
+    pub max_files: usize,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            path: None,
+            flush_interval: 60,
+            max_size: 100 * 1024 * 1024,
+            max_files: 10,
+        }
+    }
+}
+
+impl LedgerConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.flush_interval)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Seconds to let in-flight connections drain on SIGTERM/SIGINT before
+    /// they are force-closed
+    pub grace_period: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { grace_period: 30 }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectConfig {
+    /// Milliseconds to wait for a connection attempt before starting the
+    /// next one in parallel
+    pub attempt_delay_ms: u64,
+    /// Seconds until all connection attempts to upstream are given up
+    pub timeout: u64,
+    /// Seconds to try an address that failed to connect after others
+    pub failure_ttl: u64,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        ConnectConfig {
+            attempt_delay_ms: 250,
+            timeout: 10,
+            failure_ttl: 60,
+        }
+    }
+}
+
+impl ConnectConfig {
+    pub fn attempt_delay(&self) -> Duration {
+        Duration::from_millis(self.attempt_delay_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    pub fn failure_ttl(&self) -> Duration {
+        Duration::from_secs(self.failure_ttl)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Addresses to route plaintext HTTP by Host header on, disabled if empty
+    pub listen: Vec<SocketAddr>,
+    /// Port to forward ACME HTTP-01 challenges to on upstream hosts
+    pub upstream_port: u16,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: Vec::new(),
+            upstream_port: 80,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuicConfig {
+    /// UDP addresses to route QUIC by SNI on, disabled if empty
+    pub listen: Vec<SocketAddr>,
+    /// Seconds without datagrams in either direction before flow expires
+    pub idle_timeout: u64,
+    /// Max number of concurrent flows, new ones are dropped over the limit
+    pub max_flows: usize,
+    /// Max bytes of Initial datagrams buffered across all flows whose
+    /// ClientHello hasn't fully arrived yet, datagrams are dropped over it
+    pub max_pending_bytes: usize,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        QuicConfig {
+            listen: Vec::new(),
+            idle_timeout: 30,
+            max_flows: 65536,
+            max_pending_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl QuicConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Max number of concurrent connections overall
+    pub max_connections: usize,
+    /// Max number of concurrent connections per source network
+    pub max_per_network: usize,
+    /// Prefix length that source IPv4 addresses are aggregated by
+    pub ipv4_prefix: u8,
+    /// Prefix length that source IPv6 addresses are aggregated by
+    pub ipv6_prefix: u8,
+    /// Max number of concurrent connections per destination hostname
+    pub max_per_hostname: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: 16384,
+            max_per_network: 256,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            max_per_hostname: 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// New connections per second allowed per source network
+    pub rate: f64,
+    /// Max number of new connections per source network in a burst
+    pub burst: u32,
+    /// Prefix length that source IPv4 addresses are aggregated by
+    pub ipv4_prefix: u8,
+    /// Prefix length that source IPv6 addresses are aggregated by
+    pub ipv6_prefix: u8,
+    /// Networks that are never rate limited, such as monitoring probes
+    pub allowlist: Vec<IpNet>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            rate: 20.0,
+            burst: 100,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            allowlist: Vec::new(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["[::]:443".parse().unwrap()],
+            allowed_suffixes: vec![".holohost.net".into()],
+            nested_labels: false,
+            upstream_port: 443,
+            proxy_protocol: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            handshake: HandshakeConfig::default(),
+            splice: SpliceConfig::default(),
+            resolver: ResolverConfig::default(),
+            connect: ConnectConfig::default(),
+            routes: ReloadableFileConfig::default(),
+            acl: ReloadableFileConfig::default(),
+            blocklist: ReloadableFileConfig::default(),
+            metrics: MetricsConfig::default(),
+            ledger: LedgerConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            http: HttpConfig::default(),
+            quic: QuicConfig::default(),
+            limits: LimitsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads config file (if any), applies CLI and env overrides on top of it,
+    /// and validates the result.
+    pub fn load(opt: &Opt) -> Fallible<Self> {
+        let mut config = match &opt.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if !opt.listen.is_empty() {
+            config.listen = opt.listen.clone();
+        }
+
+        if !opt.allowed_suffix.is_empty() {
+            config.allowed_suffixes = opt.allowed_suffix.clone();
+        }
+
+        if let Some(port) = opt.upstream_port {
+            config.upstream_port = port;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Fallible<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        let config = toml::from_str(&contents)
+            .with_context(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Fallible<()> {
+        if self.listen.is_empty() {
+            bail!("At least one listen address is required");
+        }
+
+        if self.allowed_suffixes.is_empty() {
+            bail!("At least one allowed suffix is required");
+        }
+
+        for suffix in &self.allowed_suffixes {
+            if !suffix.starts_with('.') || suffix.len() < 2 {
+                bail!("Allowed suffix {:?} must start with a dot", suffix);
+            }
+
+            if suffix.chars().any(|c| c.is_ascii_uppercase()) {
+                bail!("Allowed suffix {:?} must be lowercase", suffix);
+            }
+        }
+
+        for suffix in self.proxy_protocol.keys() {
+            if !suffix.starts_with('.') || suffix.chars().any(|c| c.is_ascii_uppercase()) {
+                bail!(
+                    "PROXY protocol suffix {:?} must be lowercase and start with a dot",
+                    suffix
+                );
+            }
+        }
+
+        if self.upstream_port == 0 {
+            bail!("Upstream port must be non-zero");
+        }
+
+        if self.handshake.max_length < 512 {
+            bail!("Handshake max length must be at least 512 bytes");
+        }
+
+        if self.handshake.timeout == 0 {
+            bail!("Handshake timeout must be non-zero");
+        }
+
+        if self.handshake.max_per_ip == 0 {
+            bail!("Handshake max per IP must be non-zero");
+        }
+
+        if self.splice.idle_timeout == 0 || self.splice.max_lifetime == 0 {
+            bail!("Splice timeouts must be non-zero");
+        }
+
+        if self.connect.attempt_delay_ms == 0 || self.connect.timeout == 0 {
+            bail!("Connect attempt delay and timeout must be non-zero");
+        }
+
+        if self.http.upstream_port == 0 {
+            bail!("HTTP upstream port must be non-zero");
+        }
+
+        if self.quic.idle_timeout == 0
+            || self.quic.max_flows == 0
+            || self.quic.max_pending_bytes == 0
+        {
+            bail!("QUIC idle timeout, max flows and max pending bytes must be non-zero");
+        }
+
+        if let Some(url) = &self.resolver.doh_url {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                bail!("DoH URL {:?} must be HTTP(S)", url);
+            }
+
+            if url.contains('?') {
+                bail!("DoH URL {:?} must not have a query string", url);
+            }
+        }
+
+        if self.resolver.timeout == 0 {
+            bail!("Resolver timeout must be non-zero");
+        }
+
+        if self.resolver.cache_size == 0 {
+            bail!("Resolver cache size must be non-zero");
+        }
+
+        if [&self.routes, &self.acl, &self.blocklist]
+            .iter()
+            .any(|file| file.reload_interval == 0)
+        {
+            bail!("Reload intervals must be non-zero");
+        }
+
+        if self.ledger.flush_interval == 0 {
+            bail!("Ledger flush interval must be non-zero");
+        }
+
+        let limits = &self.limits;
+
+        if limits.max_connections == 0
+            || limits.max_per_network == 0
+            || limits.max_per_hostname == 0
+        {
+            bail!("Connection limits must be non-zero");
+        }
+
+        if limits.ipv4_prefix > 32 || limits.ipv6_prefix > 128 {
+            bail!("Source network prefix lengths must be at most 32 (IPv4) and 128 (IPv6)");
+        }
+
+        let rate_limit = &self.rate_limit;
+
+        if rate_limit.rate.is_nan() || rate_limit.rate <= 0.0 || rate_limit.burst == 0 {
+            bail!("Rate limit rate and burst must be positive");
+        }
+
+        if rate_limit.ipv4_prefix > 32 || rate_limit.ipv6_prefix > 128 {
+            bail!("Rate limit prefix lengths must be at most 32 (IPv4) and 128 (IPv6)");
+        }
+
+        Ok(())
+    }
+
+    /// PROXY protocol version for the longest suffix that matches hostname.
+    pub fn proxy_protocol_for(&self, hostname: &str) -> Option<proxy_protocol::Version> {
+        let hostname = hostname.to_ascii_lowercase();
+
+        self.proxy_protocol
+            .iter()
+            .filter(|(suffix, _)| {
+                hostname.len() > suffix.len() && hostname.ends_with(suffix.as_str())
+            })
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, version)| *version)
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let ip = ip::canonical(ip);
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn parse_agent_hostname(&self, hostname: &str) -> Result<AgentHostname, Rejection> {
+        AgentHostname::parse(hostname, &self.allowed_suffixes, self.nested_labels)
+    }
+}
@@ -0,0 +1,208 @@
+use failure::*;
+use futures::future;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tracing::*;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use crate::config::ConnectConfig;
+
+/// Number of remembered failed addresses above which expired ones are
+/// pruned.
+const MAX_FAILED: usize = 4096;
+
+/// Outbound connector that races resolved addresses with Happy Eyeballs, and
+/// remembers addresses that failed recently, so that they are tried last.
+/// See: https://tools.ietf.org/html/rfc8305
+pub struct Connector {
+    config: ConnectConfig,
+    failed: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+/// Interleaves IPv6 and IPv4 addresses, starting with IPv6, and otherwise
+/// keeping their order.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ipv6 = ipv6.into_iter();
+    let mut ipv4 = ipv4.into_iter();
+    let mut interleaved = Vec::new();
+
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+impl Connector {
+    pub fn new(config: ConnectConfig) -> Self {
+        Connector {
+            config,
+            failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn has_failed(&self, addr: &SocketAddr) -> bool {
+        match self.failed.lock().unwrap().get(addr) {
+            Some(failed_at) => failed_at.elapsed() < self.config.failure_ttl(),
+            None => false,
+        }
+    }
+
+    fn remember_failure(&self, addr: SocketAddr) {
+        let mut failed = self.failed.lock().unwrap();
+
+        if failed.len() >= MAX_FAILED {
+            let ttl = self.config.failure_ttl();
+            failed.retain(|_, failed_at| failed_at.elapsed() < ttl);
+        }
+
+        failed.insert(addr, Instant::now());
+    }
+
+    /// Orders addresses for connection attempts: interleaved by family,
+    /// with recently failed ones last.
+    fn candidates(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (failed, fresh): (Vec<_>, Vec<_>) = addrs
+            .iter()
+            .copied()
+            .partition(|addr| self.has_failed(addr));
+
+        let mut candidates = interleave(fresh);
+        candidates.extend(interleave(failed));
+        candidates
+    }
+
+    /// Connects to the first address that accepts connection. Next attempt
+    /// starts once the previous one fails or attempt delay passes, whichever
+    /// comes first, without cancelling attempts in progress. Attempts still
+    /// in progress when timeout expires are remembered as failed, and so are
+    /// the ones that another attempt wins over after their attempt delay.
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> Fallible<TcpStream> {
+        let deadline = Instant::now() + self.config.timeout();
+        self.race(self.candidates(addrs), deadline).await
+    }
+
+    async fn race(&self, candidates: Vec<SocketAddr>, deadline: Instant) -> Fallible<TcpStream> {
+        let mut candidates = candidates.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut in_progress = HashMap::new();
+        let mut last_error = None;
+        let mut start_next = true;
+
+        loop {
+            if start_next {
+                if let Some(addr) = candidates.next() {
+                    debug!("Connecting to {}", addr);
+                    in_progress.insert(addr, Instant::now());
+                    attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+                }
+
+                start_next = false;
+            }
+
+            if attempts.is_empty() {
+                break;
+            }
+
+            let has_candidates = !candidates.as_slice().is_empty();
+            let attempt_delay = async {
+                if has_candidates {
+                    time::delay_for(self.config.attempt_delay()).await
+                } else {
+                    future::pending::<()>().await
+                }
+            };
+
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => {
+                    in_progress.remove(&addr);
+
+                    match result {
+                        Ok(stream) => {
+                            self.failed.lock().unwrap().remove(&addr);
+
+                            for (&addr, started) in &in_progress {
+                                if started.elapsed() >= self.config.attempt_delay() {
+                                    debug!("Connecting to {} is taking too long", addr);
+                                    self.remember_failure(addr);
+                                }
+                            }
+
+                            return Ok(stream);
+                        }
+                        Err(e) => {
+                            debug!("Failed to connect to {}: {}", addr, e);
+                            self.remember_failure(addr);
+                            last_error = Some(e);
+                            start_next = true;
+                        }
+                    }
+                }
+                _ = attempt_delay => start_next = true,
+                _ = time::delay_until(deadline) => {
+                    for &addr in in_progress.keys() {
+                        debug!("Timed out connecting to {}", addr);
+                        self.remember_failure(addr);
+                    }
+
+                    bail!("Timed out connecting after {:?}", self.config.timeout());
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => bail!("No addresses to connect to"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Address in TEST-NET-1, which either drops SYNs or is unreachable.
+    const BLACKHOLE: &str = "192.0.2.1:443";
+
+    fn connector(timeout: u64) -> Connector {
+        Connector::new(ConnectConfig {
+            attempt_delay_ms: 50,
+            timeout,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn remembers_attempts_that_are_won_over() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let blackhole = BLACKHOLE.parse().unwrap();
+        let connector = connector(5);
+
+        let stream = connector.connect(&[blackhole, reachable]).await.unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        assert!(connector.has_failed(&blackhole));
+        assert!(!connector.has_failed(&reachable));
+        assert_eq!(
+            connector.candidates(&[blackhole, reachable]),
+            [reachable, blackhole]
+        );
+    }
+
+    #[tokio::test]
+    async fn remembers_attempts_in_progress_on_timeout() {
+        let blackhole = BLACKHOLE.parse().unwrap();
+        let connector = connector(1);
+
+        assert!(connector.connect(&[blackhole]).await.is_err());
+        assert!(connector.has_failed(&blackhole));
+    }
+}
@@ -0,0 +1,183 @@
+//! Just enough of DNS wire format (RFC 1035) to ask for A/AAAA records and
+//! read addresses and negative caching TTL (RFC 2308) from the response.
+
+use failure::*;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+
+const CLASS_IN: u16 = 1;
+const FLAG_RD: u16 = 0x0100;
+const HEADER_LENGTH: usize = 12;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+#[derive(Debug)]
+pub struct Response {
+    pub rcode: u8,
+    /// Addresses with their TTLs in seconds
+    pub addrs: Vec<(IpAddr, u32)>,
+    /// TTL derived from SOA record in authority section, if any
+    pub negative_ttl: Option<u32>,
+}
+
+/// Encodes query for a single question. ID is always 0, as recommended by
+/// RFC 8484 for cache friendliness.
+pub fn encode_query(name: &str, qtype: u16) -> Fallible<Vec<u8>> {
+    let mut buf = Vec::with_capacity(HEADER_LENGTH + name.len() + 6);
+
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid DNS name: {}", name);
+        }
+
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(buf)
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Fallible<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            bail!("DNS message is truncated");
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Fallible<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Fallible<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Fallible<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Skips (possibly compressed) name, without following pointers.
+    fn skip_name(&mut self) -> Fallible<()> {
+        loop {
+            let len = self.u8()?;
+
+            match len & 0xc0 {
+                0x00 if len == 0 => return Ok(()),
+                0x00 => {
+                    self.take(usize::from(len))?;
+                }
+                0xc0 => {
+                    self.u8()?;
+                    return Ok(());
+                }
+                _ => bail!("Invalid DNS label type"),
+            }
+        }
+    }
+}
+
+pub fn decode_response(buf: &[u8]) -> Fallible<Response> {
+    let mut rd = Cursor { buf, pos: 0 };
+
+    let _id = rd.u16()?;
+    let flags = rd.u16()?;
+    let qdcount = rd.u16()?;
+    let ancount = rd.u16()?;
+    let nscount = rd.u16()?;
+    let _arcount = rd.u16()?;
+
+    if flags & 0x8000 == 0 {
+        bail!("DNS message is not a response");
+    }
+
+    let rcode = (flags & 0x000f) as u8;
+
+    for _ in 0..qdcount {
+        rd.skip_name()?;
+        rd.take(4)?;
+    }
+
+    let mut addrs = Vec::new();
+
+    for _ in 0..ancount {
+        rd.skip_name()?;
+
+        let rtype = rd.u16()?;
+        let _class = rd.u16()?;
+        let ttl = rd.u32()?;
+        let rdata = rd.take(usize::from(rd.u16()?))?;
+
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets = [rdata[0], rdata[1], rdata[2], rdata[3]];
+                addrs.push((IpAddr::V4(Ipv4Addr::from(octets)), ttl));
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(rdata);
+                addrs.push((IpAddr::V6(Ipv6Addr::from(octets)), ttl));
+            }
+            _ => {}
+        }
+    }
+
+    let mut negative_ttl = None;
+
+    for _ in 0..nscount {
+        rd.skip_name()?;
+
+        let rtype = rd.u16()?;
+        let _class = rd.u16()?;
+        let ttl = rd.u32()?;
+        let rdlength = usize::from(rd.u16()?);
+
+        if rtype != TYPE_SOA {
+            rd.take(rdlength)?;
+            continue;
+        }
+
+        let end = rd.pos + rdlength;
+
+        rd.skip_name()?;
+        rd.skip_name()?;
+        rd.take(16)?;
+
+        let minimum = rd.u32()?;
+        negative_ttl = Some(ttl.min(minimum));
+
+        if rd.pos != end {
+            bail!("SOA record length mismatch");
+        }
+    }
+
+    Ok(Response {
+        rcode,
+        addrs,
+        negative_ttl,
+    })
+}
@@ -0,0 +1,38 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::ip;
+use crate::limits::{KeyedGuard, KeyedLimiter};
+
+/// Tracks connections that are still in the handshake phase (between accept
+/// and SNI extraction), and caps how many of them a single source IP may hold.
+pub struct HandshakeLimiter {
+    in_progress: KeyedLimiter<IpAddr>,
+}
+
+/// Released when dropped, which should happen as soon as SNI is extracted.
+pub struct HandshakeGuard {
+    started: Instant,
+    _in_progress: KeyedGuard<IpAddr>,
+}
+
+impl HandshakeLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        HandshakeLimiter {
+            in_progress: KeyedLimiter::new(max_per_ip),
+        }
+    }
+
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<HandshakeGuard> {
+        Some(HandshakeGuard {
+            started: Instant::now(),
+            _in_progress: self.in_progress.try_acquire(ip::canonical(ip))?,
+        })
+    }
+}
+
+impl HandshakeGuard {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
@@ -0,0 +1,187 @@
+use std::ops::{Range, RangeInclusive};
+
+use crate::rejection::Rejection;
+
+/// Length of Base36-encoded Ed25519 public key. It is 50 digits for most
+/// keys, and shorter for keys that start with small bytes.
+const AGENT_ID_LENGTH: RangeInclusive<usize> = 48..=50;
+
+/// Largest 256-bit value in Base36. Digits are in ASCII order, so
+/// same-length ids can be compared to it as strings.
+const AGENT_ID_MAX: &str = "6dp5qcb22im238nr3wvp0ic7q99w035jmy2iw7i6n43d37jtof";
+
+fn is_agent_id(label: &str) -> bool {
+    AGENT_ID_LENGTH.contains(&label.len())
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b.is_ascii_lowercase())
+        && (label.len() < AGENT_ID_MAX.len() || label <= AGENT_ID_MAX)
+}
+
+fn is_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Lowercases hostname and strips trailing dot, so that all spellings of a
+/// name are keyed the same.
+pub fn canonical(hostname: &str) -> String {
+    let hostname = if hostname.ends_with('.') {
+        &hostname[..hostname.len() - 1]
+    } else {
+        hostname
+    };
+
+    hostname.to_ascii_lowercase()
+}
+
+/// Hostname of a Holochain agent: `<agent-id><suffix>`, where agent id is
+/// Base36-encoded public key and suffix is one of allowed suffixes. With
+/// nested labels allowed, app labels may precede agent id, as in
+/// `<app>.<agent-id><suffix>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgentHostname {
+    hostname: String,
+    agent_id: Range<usize>,
+}
+
+impl AgentHostname {
+    /// Validates hostname before any DNS lookup. Hostname is canonicalized.
+    pub fn parse(
+        hostname: &str,
+        allowed_suffixes: &[String],
+        nested_labels: bool,
+    ) -> Result<Self, Rejection> {
+        let hostname = canonical(hostname);
+
+        let suffix = allowed_suffixes
+            .iter()
+            .find(|suffix| hostname.len() > suffix.len() && hostname.ends_with(suffix.as_str()))
+            .ok_or_else(|| Rejection::DisallowedHostname(hostname.clone()))?;
+
+        let labels = &hostname[..hostname.len() - suffix.len()];
+        let invalid = || Rejection::InvalidAgentHostname(hostname.clone());
+
+        let (apps, agent_id) = match labels.rfind('.') {
+            Some(_) if !nested_labels => return Err(invalid()),
+            Some(i) => (Some(&labels[..i]), &labels[i + 1..]),
+            None => (None, labels),
+        };
+
+        if !is_agent_id(agent_id) || !apps.map_or(true, |apps| apps.split('.').all(is_label)) {
+            return Err(invalid());
+        }
+
+        let start = labels.len() - agent_id.len();
+
+        Ok(AgentHostname {
+            agent_id: start..labels.len(),
+            hostname,
+        })
+    }
+
+    pub fn agent_id(&self) -> &str {
+        &self.hostname[self.agent_id.clone()]
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.hostname
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AGENT_ID: &str = "5m5srup6m3b2iilrsqmxu6ydp8p8cr0rdbh4wamupk3s4sxqr5";
+
+    fn parse(hostname: &str, nested_labels: bool) -> Option<AgentHostname> {
+        AgentHostname::parse(hostname, &[".holohost.net".into()], nested_labels).ok()
+    }
+
+    #[test]
+    fn accepts_agent_id_lengths() {
+        // Only 50-digit ids can exceed the largest 256-bit value:
+        for agent_id in &["z".repeat(48), "z".repeat(49), AGENT_ID.to_string()] {
+            let agent = parse(&format!("{}.holohost.net", agent_id), false).unwrap();
+            assert_eq!(agent.agent_id(), agent_id);
+        }
+    }
+
+    #[test]
+    fn rejects_agent_id_lengths() {
+        for &length in &[0, 47, 51] {
+            let hostname = format!("{}.holohost.net", "1".repeat(length));
+            assert!(parse(&hostname, false).is_none(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn rejects_agent_id_above_max() {
+        assert!(parse(&format!("{}.holohost.net", AGENT_ID_MAX), false).is_some());
+
+        let above = "6dp5qcb22im238nr3wvp0ic7q99w035jmy2iw7i6n43d37jtog";
+        assert!(above > AGENT_ID_MAX);
+        assert!(parse(&format!("{}.holohost.net", above), false).is_none());
+
+        let above = "7".to_string() + &"0".repeat(49);
+        assert!(parse(&format!("{}.holohost.net", above), false).is_none());
+    }
+
+    #[test]
+    fn rejects_non_base36_agent_id() {
+        for c in &["-", "_", "+", "/", "é"] {
+            let agent_id = c.to_string() + &AGENT_ID[c.len()..];
+            let hostname = format!("{}.holohost.net", agent_id);
+            assert!(parse(&hostname, false).is_none(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn canonicalizes_case_and_trailing_dot() {
+        let expected = format!("{}.holohost.net", AGENT_ID);
+
+        for hostname in &[
+            format!("{}.HoloHost.NET", AGENT_ID.to_ascii_uppercase()),
+            format!("{}.holohost.net.", AGENT_ID),
+        ] {
+            let agent = parse(hostname, false).unwrap();
+            assert_eq!(agent.as_str(), expected);
+            assert_eq!(agent.agent_id(), AGENT_ID);
+            assert_eq!(canonical(hostname), expected);
+        }
+
+        assert!(parse(&format!("{}.holohost.net..", AGENT_ID), false).is_none());
+    }
+
+    #[test]
+    fn parses_nested_labels_only_if_allowed() {
+        let hostname = format!("app.v2.{}.holohost.net", AGENT_ID);
+
+        assert!(parse(&hostname, false).is_none());
+
+        let agent = parse(&hostname, true).unwrap();
+        assert_eq!(agent.agent_id(), AGENT_ID);
+
+        // Agent id is the label right before suffix, not the leftmost one:
+        let hostname = format!("{}.app.holohost.net", AGENT_ID);
+        assert!(parse(&hostname, true).is_none());
+
+        for apps in &["-app", "app-", "", "a_b"] {
+            let hostname = format!("{}.{}.holohost.net", apps, AGENT_ID);
+            assert!(parse(&hostname, true).is_none(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn rejects_disallowed_suffix() {
+        assert!(parse(&format!("{}.holohost.org", AGENT_ID), false).is_none());
+        assert!(parse(&format!("{}holohost.net", AGENT_ID), false).is_none());
+        assert!(parse(".holohost.net", false).is_none());
+    }
+}
@@ -0,0 +1,170 @@
+use failure::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use std::io;
+use std::net::Shutdown;
+use std::str;
+
+use crate::peek::peek_until;
+use crate::rejection::Rejection;
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+/// Path prefix of HTTP-01 ACME challenges.
+/// See: https://tools.ietf.org/html/rfc8555#section-8.3
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Request line and `Host` header of HTTP/1.x request, which is all that
+/// gateway dispatches by.
+#[derive(Debug)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    /// Host without port, lowercased
+    pub host: String,
+    /// Length of request head, including empty line
+    pub length: usize,
+}
+
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(HEAD_END.len())
+        .position(|window| window == HEAD_END)
+        .map(|i| i + HEAD_END.len())
+}
+
+fn parse_host(value: &str) -> Result<String, Rejection> {
+    let value = value.trim();
+
+    // Hostnames can't have brackets, so IPv6 literals are rejected here too:
+    let host = match value.rfind(':') {
+        Some(i) if value[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &value[..i],
+        _ => value,
+    };
+
+    if host.is_empty()
+        || !host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+    {
+        return Err(Rejection::MalformedHttpRequest("invalid Host header"));
+    }
+
+    Ok(host.to_ascii_lowercase())
+}
+
+fn parse_request_head(buf: &[u8], length: usize) -> Result<RequestHead, Rejection> {
+    let head = str::from_utf8(&buf[..length])
+        .map_err(|_| Rejection::MalformedHttpRequest("request head is not UTF-8"))?;
+
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+
+    let (method, path, version) = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(path), Some(version), None) => (method, path, version),
+        _ => return Err(Rejection::MalformedHttpRequest("invalid request line")),
+    };
+
+    if !version.starts_with("HTTP/1.") {
+        return Err(Rejection::MalformedHttpRequest("unsupported HTTP version"));
+    }
+
+    // Browsers only send absolute-form request targets to proxies:
+    if !path.starts_with('/') {
+        return Err(Rejection::MalformedHttpRequest(
+            "request target is not a path",
+        ));
+    }
+
+    let mut host = None;
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let mut header = line.splitn(2, ':');
+
+        match (header.next(), header.next()) {
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case("host") => {
+                if host.replace(parse_host(value)?).is_some() {
+                    return Err(Rejection::MalformedHttpRequest("duplicate Host header"));
+                }
+            }
+            (Some(_), Some(_)) => (),
+            _ => return Err(Rejection::MalformedHttpRequest("invalid header line")),
+        }
+    }
+
+    Ok(RequestHead {
+        method: method.into(),
+        path: path.into(),
+        host: host.ok_or(Rejection::MissingHost)?,
+        length,
+    })
+}
+
+/// Peeks (without consuming) HTTP request head, so that it can be forwarded
+/// as is. `max_length` caps its size, and `deadline` caps the time spent
+/// waiting for it.
+pub async fn read_request_head(
+    stream: &mut TcpStream,
+    max_length: usize,
+    deadline: Instant,
+) -> Fallible<RequestHead> {
+    let buf = peek_until(stream, max_length, deadline, |buf| {
+        find_head_end(buf).is_some()
+    })
+    .await?;
+
+    let length =
+        find_head_end(&buf).ok_or(Rejection::MalformedHttpRequest("request head is too long"))?;
+
+    Ok(parse_request_head(&buf, length)?)
+}
+
+/// Consumes request head and writes response without body, closing write
+/// half of the stream afterwards.
+async fn respond(
+    stream: &mut TcpStream,
+    head_length: usize,
+    status: &str,
+    location: Option<&str>,
+) -> io::Result<()> {
+    let mut head = vec![0; head_length];
+    stream.read_exact(&mut head).await?;
+
+    let location = location
+        .map(|location| format!("Location: {}\r\n", location))
+        .unwrap_or_default();
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, location
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Redirects request to the same URL over HTTPS. Requests other than GET and
+/// HEAD get permanent redirect that preserves method and body.
+pub async fn redirect_to_https(stream: &mut TcpStream, head: &RequestHead) -> io::Result<()> {
+    let status = match head.method.as_str() {
+        "GET" | "HEAD" => "301 Moved Permanently",
+        _ => "308 Permanent Redirect",
+    };
+
+    let location = format!("https://{}{}", head.host, head.path);
+    respond(stream, head.length, status, Some(&location)).await
+}
+
+/// Responds with error status. Request head may have not been received in
+/// full, so whatever is buffered is consumed instead.
+pub async fn send_error(stream: &mut TcpStream, status: &str) -> io::Result<()> {
+    let mut buf = vec![0; 4096];
+    let buffered = stream.peek(&mut buf).await?;
+    respond(stream, buffered, status, None).await
+}
@@ -0,0 +1,13 @@
+use std::net::IpAddr;
+
+/// Unwraps IPv4-mapped IPv6 addresses (`::ffff:1.2.3.4`), which is how IPv4
+/// peers show up on dual-stack `[::]` listeners, so that they match IPv4 rules.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
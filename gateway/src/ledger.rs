@@ -0,0 +1,376 @@
+//! Per-hostname traffic accounting for host billing. Usage is accumulated in
+//! memory and periodically appended to a JSON lines ledger file, which is
+//! rotated by size. Open connections are accounted at every flush, so that
+//! traffic of long-lived ones is spread over intervals it happened in.
+
+use failure::*;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tokio::time;
+use tracing::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::LedgerConfig;
+use crate::splice::{Counters, Transfer};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Usage {
+    pub connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub connection_seconds: f64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.connections += other.connections;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.connection_seconds += other.connection_seconds;
+    }
+}
+
+/// Usage of a single hostname over `[start, end)` interval, in Unix time
+/// milliseconds.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    start: u128,
+    end: u128,
+    hostname: String,
+    #[serde(flatten)]
+    usage: Usage,
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// Open connection, with traffic and time that has already been accounted.
+struct Active {
+    hostname: String,
+    counters: Arc<Counters>,
+    accounted: Transfer,
+    since: SystemTime,
+}
+
+impl Active {
+    /// Returns usage since previous call.
+    fn take(&mut self, now: SystemTime) -> Usage {
+        let transfer = self.counters.load();
+
+        let usage = Usage {
+            connections: 0,
+            bytes_in: transfer.inbound_to_outbound - self.accounted.inbound_to_outbound,
+            bytes_out: transfer.outbound_to_inbound - self.accounted.outbound_to_inbound,
+            connection_seconds: now
+                .duration_since(self.since)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        };
+
+        self.accounted = transfer;
+        self.since = now;
+
+        usage
+    }
+}
+
+struct Pending {
+    start: SystemTime,
+    usage: HashMap<String, Usage>,
+    active: HashMap<u64, Active>,
+    next_id: u64,
+}
+
+impl Pending {
+    fn add(&mut self, hostname: &str, usage: &Usage) {
+        self.usage.entry(hostname.into()).or_default().add(usage);
+    }
+}
+
+pub struct Ledger {
+    config: LedgerConfig,
+    path: PathBuf,
+    pending: Mutex<Pending>,
+}
+
+/// Connection that is accounted while it is held, with its remaining usage
+/// accounted when it is dropped.
+pub struct Account<'a> {
+    ledger: &'a Ledger,
+    id: u64,
+}
+
+impl Drop for Account<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.ledger.pending.lock().unwrap();
+
+        if let Some(mut active) = pending.active.remove(&self.id) {
+            let usage = active.take(SystemTime::now());
+            pending.add(&active.hostname, &usage);
+        }
+    }
+}
+
+impl Ledger {
+    pub fn new(path: PathBuf, config: LedgerConfig) -> Self {
+        Ledger {
+            config,
+            path,
+            pending: Mutex::new(Pending {
+                start: SystemTime::now(),
+                usage: HashMap::new(),
+                active: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Starts accounting a connection to hostname, by `counters` that splice
+    /// updates as data is transferred.
+    pub fn open(&self, hostname: &str, counters: Arc<Counters>) -> Account<'_> {
+        let mut pending = self.pending.lock().unwrap();
+        let hostname = hostname.to_ascii_lowercase();
+        let id = pending.next_id;
+
+        pending.next_id += 1;
+        pending.add(
+            &hostname,
+            &Usage {
+                connections: 1,
+                ..Default::default()
+            },
+        );
+        pending.active.insert(
+            id,
+            Active {
+                hostname,
+                counters,
+                accounted: Transfer::default(),
+                since: SystemTime::now(),
+            },
+        );
+
+        Account { ledger: self, id }
+    }
+
+    /// Appends usage accumulated since previous flush to the ledger,
+    /// including that of connections that are still open.
+    pub async fn flush(&self) -> Fallible<()> {
+        let (start, end, usage) = {
+            let mut pending = self.pending.lock().unwrap();
+            let now = SystemTime::now();
+
+            let active: Vec<_> = pending
+                .active
+                .values_mut()
+                .map(|active| (active.hostname.clone(), active.take(now)))
+                .collect();
+
+            for (hostname, usage) in active {
+                pending.add(&hostname, &usage);
+            }
+
+            let start = mem::replace(&mut pending.start, now);
+            (start, now, mem::take(&mut pending.usage))
+        };
+
+        if usage.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+
+        for (hostname, usage) in usage {
+            let entry = Entry {
+                start: unix_millis(start),
+                end: unix_millis(end),
+                hostname,
+                usage,
+            };
+
+            serde_json::to_writer(&mut buf, &entry)?;
+            buf.push(b'\n');
+        }
+
+        let path = self.path.clone();
+        let config = self.config.clone();
+
+        task::spawn_blocking(move || append(&path, &config, &buf)).await?
+    }
+
+    pub async fn run(&self) {
+        loop {
+            time::delay_for(self.config.flush_interval()).await;
+
+            if let Err(e) = self.flush().await {
+                error!("Failed to flush ledger: {}", e);
+            }
+        }
+    }
+}
+
+/// Appends serialized entries to the ledger, rotating it first if needed.
+/// Blocks on file system, so has to run outside of async tasks.
+fn append(path: &Path, config: &LedgerConfig, buf: &[u8]) -> Fallible<()> {
+    rotate(path, config)?;
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    // Single write, so that a crash can't leave partial entries behind:
+    file.write_all(buf)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Renames ledger to `<path>.<unix time>` once it reaches max size, and
+/// removes oldest rotated files beyond max count.
+fn rotate(path: &Path, config: &LedgerConfig) -> Fallible<()> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() >= config.max_size => {}
+        _ => return Ok(()),
+    }
+
+    let rotated = format!("{}.{}", path.display(), unix_millis(SystemTime::now()));
+    fs::rename(path, &rotated)?;
+    info!("Rotated ledger to {}", rotated);
+
+    let mut files = rotated_files(path)?;
+
+    while files.len() > config.max_files {
+        let oldest = files.remove(0);
+        fs::remove_file(&oldest)?;
+        info!("Removed {}", oldest.display());
+    }
+
+    Ok(())
+}
+
+/// Rotated ledger files, oldest first.
+fn rotated_files(path: &Path) -> Fallible<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let prefix = format!(
+        "{}.",
+        path.file_name()
+            .ok_or(err_msg("Ledger path has no file name"))?
+            .to_string_lossy()
+    );
+
+    let mut files: Vec<(u128, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if name.starts_with(&prefix) {
+            if let Ok(timestamp) = name[prefix.len()..].parse() {
+                files.push((timestamp, entry.path()));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Sums usage per hostname over ledger entries that overlap with
+/// `[from, to)`. Entries are attributed as a whole, so precision is bounded
+/// by flush interval.
+pub fn summarize(
+    path: &Path,
+    from: Option<SystemTime>,
+    to: Option<SystemTime>,
+) -> Fallible<Vec<(String, Usage)>> {
+    let from = from.map(unix_millis).unwrap_or(0);
+    let to = to.map(unix_millis).unwrap_or(u128::max_value());
+
+    let mut paths = rotated_files(path)?;
+    paths.push(path.to_path_buf());
+
+    let mut totals: HashMap<String, Usage> = HashMap::new();
+
+    for path in paths.iter().filter(|path| path.exists()) {
+        for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let entry: Entry = serde_json::from_str(&line?)
+                .with_context(|e| format!("{}:{}: {}", path.display(), n + 1, e))?;
+
+            if entry.end > from && entry.start < to {
+                totals.entry(entry.hostname).or_default().add(&entry.usage);
+            }
+        }
+    }
+
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|(_, a), (_, b)| (b.bytes_in + b.bytes_out).cmp(&(a.bytes_in + a.bytes_out)));
+
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::Ordering;
+    use uuid::Uuid;
+
+    fn read(path: &Path) -> Vec<Entry> {
+        BufReader::new(File::open(path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn accounts_open_connections_at_every_flush() {
+        let path = env::temp_dir().join(format!("ledger-{}.jsonl", Uuid::new_v4()));
+        let ledger = Ledger::new(path.clone(), LedgerConfig::default());
+        let counters = Arc::new(Counters::default());
+
+        let account = ledger.open("Example.com", counters.clone());
+        counters
+            .inbound_to_outbound
+            .fetch_add(100, Ordering::Relaxed);
+        ledger.flush().await.unwrap();
+
+        counters
+            .inbound_to_outbound
+            .fetch_add(50, Ordering::Relaxed);
+        counters.outbound_to_inbound.fetch_add(7, Ordering::Relaxed);
+        drop(account);
+        ledger.flush().await.unwrap();
+
+        let entries = read(&path);
+        fs::remove_file(&path).unwrap();
+
+        let usage: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                let usage = &entry.usage;
+                (
+                    entry.hostname.as_str(),
+                    usage.connections,
+                    usage.bytes_in,
+                    usage.bytes_out,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            usage,
+            [("example.com", 1, 100, 0), ("example.com", 0, 50, 7)]
+        );
+        assert_eq!(entries[0].end, entries[1].start);
+    }
+}
@@ -0,0 +1,139 @@
+use ipnet::IpNet;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::*;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::LimitsConfig;
+use crate::ip;
+
+/// Caps number of concurrent holders per key, such as source IP.
+pub struct KeyedLimiter<K: Eq + Hash> {
+    max_per_key: usize,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+/// Released when dropped.
+pub struct KeyedGuard<K: Eq + Hash> {
+    key: K,
+    counts: Arc<Mutex<HashMap<K, usize>>>,
+}
+
+impl<K: Clone + Eq + Hash> KeyedLimiter<K> {
+    pub fn new(max_per_key: usize) -> Self {
+        KeyedLimiter {
+            max_per_key,
+            counts: Default::default(),
+        }
+    }
+
+    pub fn try_acquire(&self, key: K) -> Option<KeyedGuard<K>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_insert(0);
+
+        if *count >= self.max_per_key {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(KeyedGuard {
+            key,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+impl<K: Eq + Hash> Drop for KeyedGuard<K> {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Warns if open file limit is lower than `max_connections` would take, in
+/// which case connections fail to be accepted or to connect upstream before
+/// the limit is ever reached. Spliced connection holds inbound and outbound
+/// sockets, plus duplicates of those and two pipes with zero-copy.
+pub fn check_open_files(config: &LimitsConfig, zero_copy: bool) {
+    let per_connection = if zero_copy { 8 } else { 2 };
+    let needed = config.max_connections * per_connection;
+
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } == -1 {
+        warn!(
+            "Failed to get open file limit: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+
+    if needed as libc::rlim_t > rlimit.rlim_cur {
+        warn!(
+            "Open file limit ({}) is too low for max_connections ({} at {} per connection), \
+             raise it to at least {}",
+            rlimit.rlim_cur, config.max_connections, per_connection, needed
+        );
+    }
+}
+
+/// Concurrent connection caps: global, per source network (IP truncated to
+/// configured prefix), and per destination hostname.
+pub struct Limits {
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    global: Semaphore,
+    per_network: KeyedLimiter<IpNet>,
+    per_hostname: KeyedLimiter<String>,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Limits {
+            ipv4_prefix: config.ipv4_prefix,
+            ipv6_prefix: config.ipv6_prefix,
+            global: Semaphore::new(config.max_connections),
+            per_network: KeyedLimiter::new(config.max_per_network),
+            per_hostname: KeyedLimiter::new(config.max_per_hostname),
+        }
+    }
+
+    pub fn try_acquire_global(&self) -> Option<SemaphorePermit<'_>> {
+        self.global.try_acquire().ok()
+    }
+
+    /// Network that source IP is aggregated into.
+    pub fn network(&self, ip: IpAddr) -> IpNet {
+        let ip = ip::canonical(ip);
+
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+
+        IpNet::new(ip, prefix).unwrap().trunc()
+    }
+
+    pub fn try_acquire_network(&self, ip: IpAddr) -> Option<KeyedGuard<IpNet>> {
+        self.per_network.try_acquire(self.network(ip))
+    }
+
+    pub fn try_acquire_hostname(&self, hostname: &str) -> Option<KeyedGuard<String>> {
+        self.per_hostname.try_acquire(hostname.to_ascii_lowercase())
+    }
+}
@@ -1,103 +1,981 @@
+mod acl;
+mod blocklist;
+mod client_hello;
+mod config;
+mod connect;
+mod dns;
+mod handshake;
+mod hostname;
+mod http;
+mod ip;
+mod ledger;
+mod limits;
+mod metrics;
+mod peek;
+mod proxy_protocol;
+mod quic;
+mod ratelimit;
+mod rejection;
+mod reload;
+mod resolver;
+mod routes;
+mod shutdown;
+mod splice;
+mod tls;
+mod udp;
+#[cfg(target_os = "linux")]
+mod zerocopy;
+
 use failure::*;
-use futures::future;
-use tokio::net::{TcpListener, TcpStream};
+use futures::future::{self, FutureExt};
+use ipnet::IpNet;
+use structopt::StructOpt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::udp::SendHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Mutex as AsyncMutex, SemaphorePermit};
+use tokio::time::{self, Duration, Instant};
 use tracing::*;
 use tracing_futures::*;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use uuid::Uuid;
 
-// See: https://tls.ulfheim.net
-use rustls::internal::msgs::codec::{Codec, Reader};
-use rustls::internal::msgs::enums::{ContentType, ProtocolVersion};
-use rustls::internal::msgs::handshake::{
-    HandshakeMessagePayload, HandshakePayload, ServerNamePayload,
-};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
-const TLS_HANDSHAKE_MAX_LENGTH: usize = 2048;
-const TLS_RECORD_HEADER_LENGTH: usize = 5;
+use acl::Acl;
+use blocklist::Blocklist;
+use client_hello::ClientHello;
+use config::{Command, Config, Opt};
+use connect::Connector;
+use handshake::{HandshakeGuard, HandshakeLimiter};
+use hostname::AgentHostname;
+use ledger::Ledger;
+use limits::{KeyedGuard, Limits};
+use metrics::Metrics;
+use proxy_protocol::Addresses;
+use ratelimit::RateLimiter;
+use rejection::Rejection;
+use reload::{Load, Reloadable};
+use resolver::Resolver;
+use routes::{Route, RouteTable};
+use shutdown::Connections;
+use splice::Counters;
+use udp::{Flow, Flows};
 
-async fn peek(stream: &mut TcpStream, size: usize) -> Fallible<Vec<u8>> {
-    let mut buf = vec![0; size];
-    let n = stream.peek(&mut buf).await?;
+/// Pause after accept fails for reasons other than the connection itself,
+/// such as running out of file descriptors, so that it doesn't spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
-    if n == size {
-        Ok(buf)
-    } else {
-        bail!("Socket peek size mismatch: {} != {}", n, size)
+/// State shared by all listeners and connections.
+struct Context {
+    acl: Option<Reloadable<Acl>>,
+    blocklist: Option<Reloadable<Blocklist>>,
+    config: Config,
+    connections: Connections,
+    connector: Connector,
+    handshakes: HandshakeLimiter,
+    ledger: Option<Ledger>,
+    limits: Limits,
+    metrics: Arc<Metrics>,
+    rate_limiter: RateLimiter,
+    resolver: Option<Resolver>,
+    routes: Option<Reloadable<RouteTable>>,
+}
+
+/// Resolves upstream host with DoH resolver if configured, or with system
+/// resolver otherwise.
+async fn resolve_upstream(
+    ctx: &Context,
+    agent: &AgentHostname,
+    port: u16,
+) -> Fallible<Vec<SocketAddr>> {
+    let hostname = agent.as_str();
+
+    let addrs: Vec<_> = match &ctx.resolver {
+        Some(resolver) => resolver
+            .resolve(hostname)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect(),
+        None => tokio::net::lookup_host((hostname, port)).await?.collect(),
+    };
+
+    if addrs.is_empty() {
+        bail!("{} resolved to no addresses", hostname);
     }
+
+    Ok(addrs)
+}
+
+/// Maps failure to resolve agent hostname (or to connect to it) to rejection
+/// that is reported to client.
+fn map_resolve_error(agent: &AgentHostname, e: Error) -> Rejection {
+    match e.downcast::<resolver::NotFound>() {
+        Ok(resolver::NotFound(hostname)) => Rejection::Unresolvable(hostname),
+        Err(e) => Rejection::UpstreamUnreachable(agent.as_str().into(), e.to_string()),
+    }
+}
+
+/// Connects to one of upstream addresses, observing connect time (but not
+/// time to resolve them) if it succeeds.
+async fn connect(ctx: &Context, addrs: &[SocketAddr]) -> Fallible<TcpStream> {
+    let started = Instant::now();
+    let outbound = ctx.connector.connect(addrs).await?;
+
+    ctx.metrics
+        .upstream_connect_seconds
+        .observe(started.elapsed().as_secs_f64());
+
+    Ok(outbound)
 }
 
-async fn splice(mut inbound: TcpStream, mut outbound: TcpStream) -> Fallible<()> {
-    let (mut ri, mut wi) = inbound.split();
-    let (mut ro, mut wo) = outbound.split();
+/// Connects to upstream host, racing resolved addresses.
+async fn connect_upstream(
+    ctx: &Context,
+    agent: &AgentHostname,
+    port: u16,
+) -> Result<TcpStream, Rejection> {
+    let result = match resolve_upstream(ctx, agent, port).await {
+        Ok(addrs) => connect(ctx, &addrs).await,
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| {
+        ctx.metrics.upstream_connect_failures.inc();
+        map_resolve_error(agent, e)
+    })
+}
+
+/// Connects to upstream address that is configured rather than resolved, such
+/// as static route or takedown notice upstream.
+async fn connect_static(ctx: &Context, upstream: SocketAddr) -> Result<TcpStream, Rejection> {
+    connect(ctx, &[upstream]).await.map_err(|e| {
+        ctx.metrics.upstream_connect_failures.inc();
+        Rejection::UpstreamUnreachable(upstream.to_string(), e.to_string())
+    })
+}
+
+/// Logs ClientHello fields that aren't dispatched by, for troubleshooting.
+fn log_client_hello(client_hello: &ClientHello) {
+    debug!(
+        "ALPN: {:?}",
+        client_hello
+            .alpn_protocols()
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+    );
+    debug!(
+        "Supported versions: {:04x?}",
+        client_hello.supported_versions().collect::<Vec<_>>()
+    );
+    debug!(
+        "Extensions: {:?}",
+        client_hello
+            .extensions()
+            .map(|extension| (extension.typ, extension.data.len()))
+            .collect::<Vec<_>>()
+    );
+}
 
-    let client_to_server = tokio::io::copy(&mut ri, &mut wo);
-    let server_to_client = tokio::io::copy(&mut ro, &mut wi);
+/// Upstream connection for a hostname, which counts towards per-hostname
+/// connection limit for as long as it is held.
+struct Dispatched {
+    outbound: TcpStream,
+    hostname: String,
+    /// Hostname is blocked, and connection goes to takedown notice upstream
+    /// instead, which isn't billed to the host
+    takedown: bool,
+    _limit: KeyedGuard<String>,
+}
 
-    future::try_join(client_to_server, server_to_client).await?;
+/// Reads ClientHello and connects to upstream host it is destined for,
+/// sending PROXY protocol header with original addresses if configured.
+async fn dispatch(
+    ctx: &Context,
+    inbound: &mut TcpStream,
+    addrs: Addresses,
+    handshake: HandshakeGuard,
+    deadline: Instant,
+) -> Fallible<Dispatched> {
+    let config = &ctx.config;
+    let handshake_message =
+        tls::read_client_hello(inbound, config.handshake.max_length, deadline).await?;
+
+    let client_hello =
+        ClientHello::parse(&handshake_message).map_err(|e| Rejection::MalformedClientHello(e.0))?;
+
+    let server_name = client_hello.server_name.ok_or(Rejection::MissingSni)?;
+    let alpn: Vec<_> = client_hello.alpn_protocols().collect();
+
+    debug!("Hostname: {}", server_name);
+    log_client_hello(&client_hello);
+
+    ctx.metrics
+        .handshake_seconds
+        .observe(handshake.elapsed().as_secs_f64());
+    drop(handshake);
+
+    // Limits, blocklist, ledger and metrics are all keyed by canonical
+    // hostname, once it is known to be either agent hostname or routed:
+    let canonical = hostname::canonical(server_name);
+    let hostname = canonical.as_str();
+
+    let routes = ctx.routes.as_ref().map(|routes| routes.get());
+    let route = routes
+        .as_ref()
+        .and_then(|routes| routes.lookup(hostname, &alpn));
+
+    let agent = match (config.parse_agent_hostname(hostname), route) {
+        (Err(e), None) => return Err(e.into()),
+        (agent, _) => agent,
+    };
+
+    let limit = ctx
+        .limits
+        .try_acquire_hostname(hostname)
+        .ok_or_else(|| Rejection::HostnameLimit(hostname.into()))?;
+
+    if let Some(blocklist) = ctx.blocklist.as_ref().map(|blocklist| blocklist.get()) {
+        if blocklist.is_blocked(hostname, agent.as_ref().ok()) {
+            let upstream = blocklist
+                .notice_upstream
+                .ok_or_else(|| Rejection::Blocked(hostname.into()))?;
+
+            let total = ctx.metrics.reject("blocked");
+            warn!(
+                reason = "blocked",
+                total = total,
+                "Serving takedown notice for {}",
+                hostname
+            );
+
+            let outbound = connect_static(ctx, upstream).await?;
+
+            return Ok(Dispatched {
+                outbound,
+                hostname: hostname.into(),
+                takedown: true,
+                _limit: limit,
+            });
+        }
+    }
+
+    let (mut outbound, proxy_version) = match route {
+        Some(Route {
+            upstream: Some(upstream),
+            proxy_protocol,
+            ..
+        }) => {
+            debug!("Static route: {}", upstream);
+
+            let outbound = connect_static(ctx, *upstream).await?;
+            (outbound, *proxy_protocol)
+        }
+        _ => {
+            let agent = agent?;
+            let port = route
+                .and_then(|route| route.upstream_port)
+                .unwrap_or(config.upstream_port);
+
+            let outbound = connect_upstream(ctx, &agent, port).await?;
+
+            // Port routes only override PROXY protocol version configured by
+            // suffix if they set one:
+            let proxy_version = match route {
+                Some(route) => {
+                    debug!("Static route to port {}", port);
+                    route
+                        .proxy_protocol
+                        .or_else(|| config.proxy_protocol_for(hostname))
+                }
+                None => config.proxy_protocol_for(hostname),
+            };
+
+            (outbound, proxy_version)
+        }
+    };
+
+    if let Some(version) = proxy_version {
+        let header = proxy_protocol::encode(version, addrs);
+        debug!("Sending PROXY protocol {:?} header", version);
+
+        outbound.write_all(&header).await?;
+    }
+
+    Ok(Dispatched {
+        outbound,
+        hostname: hostname.into(),
+        takedown: false,
+        _limit: limit,
+    })
+}
+
+/// Splices dispatched connection, accounting its traffic to the hostname.
+async fn splice_dispatched(
+    ctx: &Context,
+    inbound: TcpStream,
+    dispatched: Dispatched,
+) -> Fallible<()> {
+    let Dispatched {
+        outbound,
+        hostname,
+        takedown,
+        _limit,
+    } = dispatched;
+
+    let counters = Arc::new(Counters::default());
+    let _account = match &ctx.ledger {
+        Some(ledger) if !takedown => Some(ledger.open(&hostname, counters.clone())),
+        _ => None,
+    };
+
+    ctx.metrics.active_splices.inc();
+    let result = splice::splice(
+        inbound,
+        outbound,
+        &ctx.config.splice,
+        &counters,
+        ctx.connections.force_closed(),
+    )
+    .await;
+    ctx.metrics.active_splices.dec();
+
+    let transfer = result?;
+
+    if takedown {
+        return Ok(());
+    }
+
+    ctx.metrics.add_bytes(
+        &hostname,
+        transfer.inbound_to_outbound,
+        transfer.outbound_to_inbound,
+    );
 
     Ok(())
 }
 
-async fn splice_by_sni(mut inbound: TcpStream) -> Fallible<()> {
-    let buf = peek(&mut inbound, TLS_RECORD_HEADER_LENGTH).await?;
-    let mut rd = Reader::init(&buf);
+async fn splice_by_sni(
+    ctx: Arc<Context>,
+    mut inbound: TcpStream,
+    addrs: Addresses,
+    handshake: HandshakeGuard,
+    deadline: Instant,
+) -> Fallible<()> {
+    match dispatch(&ctx, &mut inbound, addrs, handshake, deadline).await {
+        Ok(dispatched) => splice_dispatched(&ctx, inbound, dispatched).await,
+        Err(e) => {
+            if let Some(alert) = e.downcast_ref::<Rejection>().and_then(Rejection::alert) {
+                if let Err(e) = tls::send_alert(&mut inbound, alert).await {
+                    debug!("Failed to send {:?} alert: {}", alert, e);
+                }
+            }
 
-    let content_type = ContentType::read(&mut rd).ok_or(err_msg("Failed to read content type"))?;
-    debug!("Content type: {:?}", content_type);
+            Err(e)
+        }
+    }
+}
 
-    if content_type != ContentType::Handshake {
-        bail!("Content type is not Handshake");
+/// Reads plaintext HTTP request head, and either connects to agent (or static
+/// route) it is destined for if it is an ACME HTTP-01 challenge, or redirects
+/// it to HTTPS otherwise, in which case `None` is returned.
+async fn dispatch_http(
+    ctx: &Context,
+    inbound: &mut TcpStream,
+    handshake: HandshakeGuard,
+    deadline: Instant,
+) -> Fallible<Option<Dispatched>> {
+    let config = &ctx.config;
+    let head = http::read_request_head(inbound, config.handshake.max_length, deadline).await?;
+
+    debug!(
+        "Request: {} {} (Host: {})",
+        head.method, head.path, head.host
+    );
+
+    ctx.metrics
+        .handshake_seconds
+        .observe(handshake.elapsed().as_secs_f64());
+    drop(handshake);
+
+    // Static routes are looked up first, so that routed hostnames don't have
+    // to be agent hostnames, same as in `dispatch`:
+    let canonical = hostname::canonical(&head.host);
+    let hostname = canonical.as_str();
+    let routes = ctx.routes.as_ref().map(|routes| routes.get());
+    let route = routes
+        .as_ref()
+        .and_then(|routes| routes.lookup(hostname, &[]));
+
+    let agent = match (config.parse_agent_hostname(hostname), route) {
+        (Err(e), None) => return Err(e.into()),
+        (agent, _) => agent,
+    };
+
+    if !head.path.starts_with(http::ACME_CHALLENGE_PREFIX) {
+        http::redirect_to_https(inbound, &head).await?;
+        return Ok(None);
+    }
+
+    let limit = ctx
+        .limits
+        .try_acquire_hostname(hostname)
+        .ok_or_else(|| Rejection::HostnameLimit(hostname.into()))?;
+
+    if let Some(blocklist) = &ctx.blocklist {
+        if blocklist.get().is_blocked(hostname, agent.as_ref().ok()) {
+            return Err(Rejection::Blocked(hostname.into()).into());
+        }
+    }
+
+    let outbound = match route.and_then(|route| route.upstream) {
+        // Challenges go to HTTP port of the host that route points TLS to:
+        Some(upstream) => {
+            let upstream = SocketAddr::new(upstream.ip(), config.http.upstream_port);
+            debug!("Static route: {}", upstream);
+
+            connect_static(ctx, upstream).await?
+        }
+        None => connect_upstream(ctx, &agent?, config.http.upstream_port).await?,
+    };
+
+    Ok(Some(Dispatched {
+        outbound,
+        hostname: hostname.into(),
+        takedown: false,
+        _limit: limit,
+    }))
+}
+
+async fn splice_by_host(
+    ctx: Arc<Context>,
+    mut inbound: TcpStream,
+    handshake: HandshakeGuard,
+    deadline: Instant,
+) -> Fallible<()> {
+    match dispatch_http(&ctx, &mut inbound, handshake, deadline).await {
+        Ok(Some(dispatched)) => splice_dispatched(&ctx, inbound, dispatched).await,
+        Ok(None) => Ok(()),
+        Err(e) => {
+            if let Some(status) = e
+                .downcast_ref::<Rejection>()
+                .and_then(Rejection::http_status)
+            {
+                if let Err(e) = http::send_error(&mut inbound, status).await {
+                    debug!("Failed to send {} response: {}", status, e);
+                }
+            }
+
+            Err(e)
+        }
+    }
+}
+
+/// Returns original addresses of a connection: either from PROXY protocol
+/// header if peer is a trusted proxy, or from the socket itself.
+async fn accept_addresses(
+    ctx: &Context,
+    inbound: &mut TcpStream,
+    peer_addr: SocketAddr,
+    deadline: Instant,
+) -> Fallible<Addresses> {
+    let addrs = Addresses {
+        src: peer_addr,
+        dst: inbound.local_addr()?,
+    };
+
+    if !ctx.config.is_trusted_proxy(peer_addr.ip()) {
+        return Ok(addrs);
+    }
+
+    match proxy_protocol::read_header(inbound, deadline).await? {
+        Some(proxied) => {
+            debug!("Proxy IP address: {}", peer_addr.ip());
+            Ok(proxied)
+        }
+        None => Ok(addrs),
+    }
+}
+
+fn log_error(ctx: &Context, e: &Error) {
+    if e.downcast_ref::<peek::Timeout>().is_some() {
+        let total = ctx.metrics.reject("handshake_timeout");
+        warn!(
+            reason = "handshake_timeout",
+            total = total,
+            "Dropped connection: {}",
+            e
+        );
+    } else if let Some(rejection) = e.downcast_ref::<Rejection>() {
+        ctx.metrics.reject(rejection.reason());
+
+        warn!(
+            reason = rejection.reason(),
+            "Rejected connection: {}", rejection
+        );
+    } else {
+        warn!("{}", e);
+    }
+}
+
+/// Checks client IP against ACL and connection rate limit, before any bytes
+/// are read from it.
+fn admit(ctx: &Context, ip: IpAddr) -> bool {
+    if let Some(acl) = &ctx.acl {
+        if let Err(denial) = acl.get().check(ip) {
+            let total = ctx.metrics.reject("acl");
+            warn!(
+                reason = "acl",
+                ip = %ip,
+                rule = %denial,
+                total = total,
+                "Denied connection"
+            );
+            return false;
+        }
     }
 
-    let protocol_version =
-        ProtocolVersion::read(&mut rd).ok_or(err_msg("Failed to read protocol version"))?;
-    debug!("Protocol version: {:?}", protocol_version);
+    if !ctx.rate_limiter.check(ip) {
+        let total = ctx.metrics.reject("rate_limit");
+        debug!(
+            reason = "rate_limit",
+            ip = %ip,
+            total = total,
+            "Refused connection"
+        );
+        return false;
+    }
+
+    true
+}
+
+/// Acquires a slot under global connection limit, for as long as the permit
+/// is held.
+fn acquire_global(ctx: &Context) -> Option<SemaphorePermit<'_>> {
+    let permit = ctx.limits.try_acquire_global();
+
+    if permit.is_none() {
+        let total = ctx.metrics.reject("global_limit");
+        warn!(reason = "global_limit", total = total, "Refused connection");
+    }
+
+    permit
+}
 
-    let handshake_size =
-        usize::from(u16::read(&mut rd).ok_or(err_msg("Failed to read handshake size"))?);
-    debug!("Handshake size: {:?}", handshake_size);
+/// Acquires a slot under connection limit of client's network, for as long
+/// as the guard is held.
+fn acquire_network(ctx: &Context, ip: IpAddr) -> Option<KeyedGuard<IpNet>> {
+    let guard = ctx.limits.try_acquire_network(ip);
 
-    if handshake_size > TLS_HANDSHAKE_MAX_LENGTH {
-        bail!(
-            "Handshake size is {}, while max is {}",
-            handshake_size,
-            TLS_HANDSHAKE_MAX_LENGTH
+    if guard.is_none() {
+        let total = ctx.metrics.reject("network_limit");
+        warn!(
+            reason = "network_limit",
+            network = %ctx.limits.network(ip),
+            total = total,
+            "Refused connection"
         );
     }
 
-    let buf = peek(&mut inbound, TLS_RECORD_HEADER_LENGTH + handshake_size).await?;
-    let mut rd = Reader::init(&buf);
+    guard
+}
+
+async fn handle(
+    ctx: Arc<Context>,
+    mut inbound: TcpStream,
+    peer_addr: SocketAddr,
+    deadline: Instant,
+    protocol: Protocol,
+) {
+    let addrs = match accept_addresses(&ctx, &mut inbound, peer_addr, deadline).await {
+        Ok(addrs) => addrs,
+        Err(e) => return log_error(&ctx, &e),
+    };
+
+    info!("Inbound IP address: {}", addrs.src.ip());
+
+    // Connections from trusted proxies are checked by original client
+    // address, once it is known:
+    if ctx.config.is_trusted_proxy(peer_addr.ip()) && !admit(&ctx, addrs.src.ip()) {
+        return;
+    }
+
+    let _network_limit = match acquire_network(&ctx, addrs.src.ip()) {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    let handshake = match ctx.handshakes.try_acquire(addrs.src.ip()) {
+        Some(guard) => guard,
+        None => {
+            let total = ctx.metrics.reject("handshake_limit");
+            warn!(
+                reason = "handshake_limit",
+                total = total,
+                "Dropped connection"
+            );
+            return;
+        }
+    };
+
+    let result = match protocol {
+        Protocol::Tls => splice_by_sni(ctx.clone(), inbound, addrs, handshake, deadline).await,
+        Protocol::Http => splice_by_host(ctx.clone(), inbound, handshake, deadline).await,
+    };
+
+    if let Err(e) = result {
+        log_error(&ctx, &e);
+    }
+}
+
+/// Protocol that TCP listener dispatches connections by.
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    /// TLS, by SNI
+    Tls,
+    /// Plaintext HTTP, by Host header
+    Http,
+}
+
+async fn serve(ctx: Arc<Context>, mut listener: TcpListener, protocol: Protocol) -> Fallible<()> {
+    loop {
+        let (inbound, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset => {
+                        debug!("Failed to accept connection: {}", e);
+                    }
+                    _ => {
+                        warn!("Failed to accept connection, backing off: {}", e);
+                        time::delay_for(ACCEPT_BACKOFF).await;
+                    }
+                }
+
+                continue;
+            }
+        };
+        ctx.metrics.accepted.inc();
+
+        if !ctx.config.is_trusted_proxy(peer_addr.ip()) && !admit(&ctx, peer_addr.ip()) {
+            continue;
+        }
+
+        let deadline = Instant::now() + ctx.config.handshake.timeout();
+        let ctx = ctx.clone();
+
+        let request = async move {
+            let _connection = ctx.connections.track();
+
+            let _global_limit = match acquire_global(&ctx) {
+                Some(permit) => permit,
+                None => return,
+            };
+
+            // Splice closes connection itself on force-close, so that its
+            // traffic is still accounted:
+            handle(ctx.clone(), inbound, peer_addr, deadline, protocol).await;
+        };
+
+        tokio::spawn(request.instrument(info_span!("request", uuid = ?Uuid::new_v4())));
+    }
+}
+
+/// Routes QUIC flow by its ClientHello the same way TCP connections are
+/// routed, and relays it until it expires. PROXY protocol and takedown
+/// notices don't apply to QUIC, so blocked flows are just dropped.
+async fn relay_quic(
+    ctx: &Context,
+    flow: Flow,
+    handshake: HandshakeGuard,
+    handshake_message: Vec<u8>,
+    datagrams: Vec<Vec<u8>>,
+    replies: Arc<AsyncMutex<SendHalf>>,
+) -> Fallible<()> {
+    let client_hello =
+        ClientHello::parse(&handshake_message).map_err(|e| Rejection::MalformedClientHello(e.0))?;
+
+    let server_name = client_hello.server_name.ok_or(Rejection::MissingSni)?;
+    let alpn: Vec<_> = client_hello.alpn_protocols().collect();
+
+    debug!("Hostname: {}", server_name);
+    log_client_hello(&client_hello);
+
+    ctx.metrics
+        .handshake_seconds
+        .observe(handshake.elapsed().as_secs_f64());
+    drop(handshake);
 
-    rd.take(TLS_RECORD_HEADER_LENGTH);
+    // Keyed by canonical hostname once it is validated, same as in `dispatch`:
+    let canonical = hostname::canonical(server_name);
+    let hostname = canonical.as_str();
 
-    let handshake = HandshakeMessagePayload::read_version(&mut rd, protocol_version)
-        .ok_or(err_msg("Failed to read handshake"))?;
+    let routes = ctx.routes.as_ref().map(|routes| routes.get());
+    let route = routes
+        .as_ref()
+        .and_then(|routes| routes.lookup(hostname, &alpn));
 
-    let client_hello = match handshake.payload {
-        HandshakePayload::ClientHello(x) => x,
-        _ => bail!("Handshake payload is not Client Hello"),
+    let agent = match (ctx.config.parse_agent_hostname(hostname), route) {
+        (Err(e), None) => return Err(e.into()),
+        (agent, _) => agent,
     };
 
-    let sni = client_hello
-        .get_sni_extension()
-        .ok_or(err_msg("SNI is missing"))?;
+    let _limit = ctx
+        .limits
+        .try_acquire_hostname(hostname)
+        .ok_or_else(|| Rejection::HostnameLimit(hostname.into()))?;
 
-    let hostname: &str = match &sni[0].payload {
-        ServerNamePayload::HostName(x) => x.as_ref().into(),
-        ServerNamePayload::Unknown(_) => bail!("SNI payload uses unknown format"),
+    if let Some(blocklist) = &ctx.blocklist {
+        if blocklist.get().is_blocked(hostname, agent.as_ref().ok()) {
+            return Err(Rejection::Blocked(hostname.into()).into());
+        }
+    }
+
+    let upstream = match route.and_then(|route| route.upstream) {
+        Some(upstream) => upstream,
+        None => {
+            let agent = agent?;
+            let port = route
+                .and_then(|route| route.upstream_port)
+                .unwrap_or(ctx.config.upstream_port);
+
+            let addrs = resolve_upstream(ctx, &agent, port)
+                .await
+                .map_err(|e| map_resolve_error(&agent, e))?;
+
+            // Unlike TCP, there is no way to tell whether an address is
+            // reachable, so the first one is used:
+            addrs[0]
+        }
     };
 
-    debug!("Hostname: {}", hostname);
+    debug!("QUIC upstream: {}", upstream);
+
+    let counters = Arc::new(Counters::default());
+    let _account = ctx
+        .ledger
+        .as_ref()
+        .map(|ledger| ledger.open(hostname, counters.clone()));
+
+    ctx.metrics.active_splices.inc();
+    let result = flow
+        .relay(
+            upstream,
+            datagrams,
+            replies,
+            &counters,
+            ctx.connections.force_closed(),
+        )
+        .await;
+    ctx.metrics.active_splices.dec();
+
+    let transfer = result?;
+
+    ctx.metrics.add_bytes(
+        hostname,
+        transfer.inbound_to_outbound,
+        transfer.outbound_to_inbound,
+    );
+
+    Ok(())
+}
+
+/// QUIC flow whose ClientHello hasn't fully arrived yet.
+struct PendingFlow {
+    assembler: quic::Assembler,
+    handshake: HandshakeGuard,
+}
+
+/// Routes QUIC flows by SNI. Datagrams of known flows are queued to them,
+/// while Initial datagrams of new ones are buffered until their ClientHello
+/// is complete, which can take several datagrams. Buffered datagrams are
+/// capped in total, and flows waiting for ClientHello count towards handshake
+/// limit of their source IP. Once connections start draining, new flows are
+/// ignored, while known ones are still served.
+async fn serve_quic(ctx: Arc<Context>, socket: UdpSocket) -> Fallible<()> {
+    let (mut listener, replies) = socket.split();
+    let replies = Arc::new(AsyncMutex::new(replies));
+    let flows = Arc::new(Flows::new(ctx.config.quic.clone()));
+
+    let mut pending: HashMap<(SocketAddr, Vec<u8>), PendingFlow> = HashMap::new();
+    let mut pending_bytes = 0;
+    let mut last_sweep = Instant::now();
+    let mut buf = vec![0; udp::MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (size, peer_addr) = listener.recv_from(&mut buf).await?;
+        let datagram = &buf[..size];
+
+        if let Some(mut queue) = flows.lookup(datagram, peer_addr) {
+            // Datagrams are dropped if upstream doesn't keep up, as they
+            // would be on a congested link:
+            let _ = queue.try_send((peer_addr, datagram.to_vec()));
+            continue;
+        }
+
+        if ctx.connections.is_draining() {
+            continue;
+        }
+
+        let initial = match quic::parse_initial(datagram) {
+            Ok(Some(initial)) => initial,
+            Ok(None) => continue,
+            Err(e) => {
+                debug!(ip = %peer_addr.ip(), "Dropped datagram: {}", e);
+                continue;
+            }
+        };
+
+        let timeout = ctx.config.handshake.timeout();
+
+        if last_sweep.elapsed() >= timeout {
+            pending.retain(|_, flow| {
+                let expired = flow.assembler.started.elapsed() >= timeout;
+
+                if expired {
+                    pending_bytes -= flow.assembler.size();
+                }
+
+                !expired
+            });
+            last_sweep = Instant::now();
+        }
+
+        if pending_bytes + size > ctx.config.quic.max_pending_bytes {
+            let total = ctx.metrics.reject("pending_limit");
+            warn!(
+                reason = "pending_limit",
+                total = total,
+                "Dropped QUIC datagram"
+            );
+            continue;
+        }
+
+        let key = (peer_addr, initial.dcid.clone());
+
+        if !pending.contains_key(&key) {
+            if pending.len() >= ctx.config.quic.max_flows {
+                let total = ctx.metrics.reject("flow_limit");
+                warn!(reason = "flow_limit", total = total, "Dropped QUIC flow");
+                continue;
+            }
+
+            ctx.metrics.accepted.inc();
+
+            if !admit(&ctx, peer_addr.ip()) {
+                continue;
+            }
+
+            let handshake = match ctx.handshakes.try_acquire(peer_addr.ip()) {
+                Some(guard) => guard,
+                None => {
+                    let total = ctx.metrics.reject("handshake_limit");
+                    warn!(
+                        reason = "handshake_limit",
+                        total = total,
+                        "Dropped QUIC flow"
+                    );
+                    continue;
+                }
+            };
+
+            let flow = PendingFlow {
+                assembler: quic::Assembler::default(),
+                handshake,
+            };
+
+            pending.insert(key.clone(), flow);
+        }
 
-    if !hostname.ends_with(".holohost.net") {
-        bail!("Hostname is not *.holohost.net");
+        let assembler = &mut pending.get_mut(&key).unwrap().assembler;
+        let buffered = assembler.size();
+        let result = assembler
+            .push(datagram.to_vec(), initial)
+            .and_then(|()| assembler.client_hello(ctx.config.handshake.max_length));
+        pending_bytes += assembler.size() - buffered;
+
+        let handshake_message = match result {
+            Ok(Some(handshake_message)) => handshake_message,
+            Ok(None) => continue,
+            Err(e) => {
+                pending_bytes -= pending.remove(&key).unwrap().assembler.size();
+                log_error(&ctx, &Rejection::MalformedClientHello(e.0).into());
+                continue;
+            }
+        };
+
+        let PendingFlow {
+            assembler,
+            handshake,
+        } = pending.remove(&key).unwrap();
+        pending_bytes -= assembler.size();
+
+        let flow = match flows.open(peer_addr, &key.1) {
+            Some(flow) => flow,
+            None => {
+                let total = ctx.metrics.reject("flow_limit");
+                warn!(reason = "flow_limit", total = total, "Dropped QUIC flow");
+                continue;
+            }
+        };
+
+        let ctx = ctx.clone();
+        let replies = replies.clone();
+
+        let request = async move {
+            let _connection = ctx.connections.track();
+
+            let _global_limit = match acquire_global(&ctx) {
+                Some(permit) => permit,
+                None => return,
+            };
+
+            info!("Inbound IP address: {}", peer_addr.ip());
+
+            let _network_limit = match acquire_network(&ctx, peer_addr.ip()) {
+                Some(guard) => guard,
+                None => return,
+            };
+
+            let result = relay_quic(
+                &ctx,
+                flow,
+                handshake,
+                handshake_message,
+                assembler.datagrams,
+                replies,
+            )
+            .await;
+
+            if let Err(e) = result {
+                log_error(&ctx, &e);
+            }
+        };
+
+        tokio::spawn(request.instrument(info_span!("quic", uuid = ?Uuid::new_v4())));
     }
+}
 
-    let outbound_addr = format!("{}:443", hostname);
-    let outbound = TcpStream::connect(outbound_addr).await?;
+/// Reloads file-backed part of context at runtime, if it is configured.
+fn spawn_watcher<T>(ctx: Arc<Context>, reloadable: fn(&Context) -> Option<&Reloadable<T>>)
+where
+    T: Load + Send + Sync + 'static,
+{
+    if reloadable(&ctx).is_none() {
+        return;
+    }
 
-    splice(inbound, outbound).await
+    tokio::spawn(async move {
+        if let Err(e) = reloadable(&ctx).unwrap().watch().await {
+            error!("File watcher failed: {}", e);
+        }
+    });
 }
 
 #[tokio::main]
@@ -108,19 +986,162 @@ async fn main() -> Fallible<()> {
 
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let mut listener = TcpListener::bind("[::]:443").await?;
+    let opt = Opt::from_args();
+    let mut config = Config::load(&opt)?;
 
-    loop {
-        let (inbound, inbound_addr) = listener.accept().await?;
+    if let Some(Command::Usage { ledger, from, to }) = &opt.command {
+        let path = ledger
+            .as_ref()
+            .or(config.ledger.path.as_ref())
+            .ok_or(err_msg("Ledger path is neither passed nor configured"))?;
 
-        let request = async move {
-            info!("Inbound IP address: {}", inbound_addr.ip());
+        println!(
+            "{:<64} {:>12} {:>16} {:>16} {:>18}",
+            "HOSTNAME", "CONNECTIONS", "BYTES IN", "BYTES OUT", "CONNECTION SECONDS"
+        );
+
+        for (hostname, usage) in ledger::summarize(path, *from, *to)? {
+            println!(
+                "{:<64} {:>12} {:>16} {:>16} {:>18.0}",
+                hostname,
+                usage.connections,
+                usage.bytes_in,
+                usage.bytes_out,
+                usage.connection_seconds
+            );
+        }
+
+        return Ok(());
+    }
+
+    if config.splice.zero_copy {
+        if let Err(e) = splice::check_zero_copy() {
+            warn!(
+                "Zero-copy is not supported, falling back to userspace copy: {}",
+                e
+            );
+            config.splice.zero_copy = false;
+        }
+    }
+
+    limits::check_open_files(&config.limits, config.splice.zero_copy);
 
-            if let Err(e) = splice_by_sni(inbound).in_current_span().await {
-                warn!("{}", e);
+    let ctx = Arc::new(Context {
+        acl: Reloadable::from_config(&config.acl)?,
+        blocklist: Reloadable::from_config(&config.blocklist)?,
+        connections: Connections::new(),
+        connector: Connector::new(config.connect.clone()),
+        limits: Limits::new(&config.limits),
+        rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+        ledger: config
+            .ledger
+            .path
+            .clone()
+            .map(|path| Ledger::new(path, config.ledger.clone())),
+        handshakes: HandshakeLimiter::new(config.handshake.max_per_ip),
+        resolver: config
+            .resolver
+            .doh_url
+            .clone()
+            .map(|url| Resolver::new(url, config.resolver.clone())),
+        routes: Reloadable::from_config(&config.routes)?,
+        metrics: Arc::new(Metrics::new(&config.metrics)?),
+        config,
+    });
+
+    if let Some(addr) = ctx.config.metrics.listen {
+        let metrics = ctx.metrics.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(metrics, addr).await {
+                error!("Metrics listener failed: {}", e);
             }
-        };
+        });
+    }
 
-        tokio::spawn(request.instrument(info_span!("request", uuid = ?Uuid::new_v4())));
+    if ctx.ledger.is_some() {
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            ctx.ledger.as_ref().unwrap().run().await;
+        });
     }
+
+    spawn_watcher(ctx.clone(), |ctx| ctx.acl.as_ref());
+    spawn_watcher(ctx.clone(), |ctx| ctx.blocklist.as_ref());
+    spawn_watcher(ctx.clone(), |ctx| ctx.routes.as_ref());
+
+    let mut servers = Vec::new();
+    let mut quic_servers = Vec::new();
+
+    for addr in &ctx.config.listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening on {}", addr);
+
+        servers.push(serve(ctx.clone(), listener, Protocol::Tls).boxed());
+    }
+
+    for addr in &ctx.config.http.listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening for plaintext HTTP on {}", addr);
+
+        servers.push(serve(ctx.clone(), listener, Protocol::Http).boxed());
+    }
+
+    for addr in &ctx.config.quic.listen {
+        let socket = UdpSocket::bind(addr).await?;
+        info!("Listening for QUIC on {}", addr);
+
+        quic_servers.push(serve_quic(ctx.clone(), socket));
+    }
+
+    // QUIC servers keep relaying datagrams of known flows while connections
+    // drain, so they are only dropped at exit:
+    let mut quic_servers = async {
+        future::try_join_all(quic_servers).await?;
+        future::pending::<Fallible<()>>().await
+    }
+    .boxed();
+
+    // Dropping servers closes listeners, so that no new connections are accepted:
+    let signal = tokio::select! {
+        result = future::try_join_all(servers) => {
+            result?;
+            unreachable!()
+        }
+        result = &mut quic_servers => {
+            result?;
+            unreachable!()
+        }
+        signal = shutdown::signalled() => signal?,
+    };
+
+    info!(
+        active = ctx.connections.active(),
+        "Received {}, draining connections for up to {:?}",
+        signal,
+        ctx.config.shutdown.grace_period()
+    );
+
+    let summary = tokio::select! {
+        summary = ctx.connections.drain(ctx.config.shutdown.grace_period()) => summary,
+        result = &mut quic_servers => {
+            result?;
+            unreachable!()
+        }
+    };
+
+    if let Some(ledger) = &ctx.ledger {
+        if let Err(e) = ledger.flush().await {
+            error!("Failed to flush ledger: {}", e);
+        }
+    }
+
+    info!(
+        drained = summary.drained,
+        force_closed = summary.force_closed,
+        "Shutdown complete"
+    );
+
+    Ok(())
 }
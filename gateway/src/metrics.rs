@@ -0,0 +1,190 @@
+use failure::*;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use tracing::*;
+
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::MetricsConfig;
+
+/// Label value for hostnames beyond `max_hostnames`.
+const OTHER_HOSTNAME: &str = "_other";
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    hostname_labels: bool,
+    max_hostnames: usize,
+    hostnames: Mutex<HashSet<String>>,
+    pub accepted: IntCounter,
+    pub active_splices: IntGauge,
+    pub rejections: IntCounterVec,
+    pub handshake_seconds: Histogram,
+    pub upstream_connect_seconds: Histogram,
+    pub upstream_connect_failures: IntCounter,
+    pub bytes: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new(config: &MetricsConfig) -> Fallible<Self> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("holo_router_gateway".into()), None)?,
+            hostname_labels: config.hostname_labels,
+            max_hostnames: config.max_hostnames,
+            hostnames: Default::default(),
+            accepted: IntCounter::new("accepted_connections_total", "Accepted connections")?,
+            active_splices: IntGauge::new("active_splices", "Connections currently spliced")?,
+            rejections: IntCounterVec::new(
+                Opts::new(
+                    "rejected_connections_total",
+                    "Connections rejected or dropped before splice",
+                ),
+                &["reason"],
+            )?,
+            handshake_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "handshake_seconds",
+                    "Time from admission of connection to SNI or Host header extraction",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            )?,
+            upstream_connect_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "upstream_connect_seconds",
+                    "Time to connect to upstream host, once its addresses are resolved",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            )?,
+            upstream_connect_failures: IntCounter::new(
+                "upstream_connect_failures_total",
+                "Failed attempts to resolve or connect to upstream host",
+            )?,
+            bytes: IntCounterVec::new(
+                Opts::new("bytes_total", "Bytes spliced, by direction and hostname"),
+                &["direction", "hostname"],
+            )?,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.accepted.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.active_splices.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.rejections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.handshake_seconds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.upstream_connect_seconds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.upstream_connect_failures.clone()))?;
+        metrics.registry.register(Box::new(metrics.bytes.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// Counts rejection, returning total number of rejections for the reason.
+    pub fn reject(&self, reason: &str) -> u64 {
+        let counter = self.rejections.with_label_values(&[reason]);
+        counter.inc();
+        counter.get() as u64
+    }
+
+    /// Returns hostname label value. Empty if hostname labels are disabled,
+    /// and `_other` for hostnames that don't fit into `max_hostnames`, so
+    /// that label cardinality stays bounded.
+    pub fn hostname_label(&self, hostname: &str) -> String {
+        if !self.hostname_labels {
+            return String::new();
+        }
+
+        let mut hostnames = self.hostnames.lock().unwrap();
+
+        if hostnames.contains(hostname) {
+            return hostname.into();
+        }
+
+        if hostnames.len() < self.max_hostnames {
+            hostnames.insert(hostname.into());
+            return hostname.into();
+        }
+
+        OTHER_HOSTNAME.into()
+    }
+
+    pub fn add_bytes(&self, hostname: &str, inbound: u64, outbound: u64) {
+        let label = self.hostname_label(hostname);
+
+        self.bytes
+            .with_label_values(&["in", &label])
+            .inc_by(inbound as i64);
+        self.bytes
+            .with_label_values(&["out", &label])
+            .inc_by(outbound as i64);
+    }
+
+    fn render(&self) -> Fallible<Response<Body>> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+
+        encoder.encode(&self.registry.gather(), &mut buf)?;
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buf))?)
+    }
+
+    fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let result = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => self.render(),
+            _ => Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()),
+        };
+
+        result.unwrap_or_else(|e| {
+            error!("Failed to render metrics: {}", e);
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        })
+    }
+}
+
+/// Serves `GET /metrics` in Prometheus text format on admin listener.
+pub async fn serve(metrics: Arc<Metrics>, addr: SocketAddr) -> Fallible<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = metrics.handle(req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    info!("Serving metrics on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+
+    Ok(())
+}
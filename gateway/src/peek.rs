@@ -0,0 +1,241 @@
+use failure::*;
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+
+use std::cmp;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(1);
+const BACKOFF_MAX: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Timed out waiting for {} bytes, got {}", size, received)]
+pub struct Timeout {
+    pub size: usize,
+    pub received: usize,
+}
+
+/// Peeks (without consuming) exactly `size` bytes from the stream.
+///
+/// `TcpStream::peek` returns as soon as any data is available, and keeps
+/// returning the same bytes until more arrive, so partial results are retried
+/// with exponential backoff until `size` bytes are buffered or `deadline`
+/// expires. A peer that closes the connection before that is detected once
+/// peek returns 0 bytes, or, if it has sent some bytes first, once it has
+/// shut down its side (only on Linux, elsewhere this ends in `Timeout`).
+pub async fn peek_exact(
+    stream: &mut TcpStream,
+    size: usize,
+    deadline: Instant,
+) -> Fallible<Vec<u8>> {
+    let mut buf = vec![0; size];
+    let mut backoff = BACKOFF_MIN;
+    let mut received = 0;
+    let mut closed = false;
+
+    loop {
+        let n = match time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Timeout { size, received }.into()),
+        };
+
+        if n == size {
+            return Ok(buf);
+        }
+
+        if n == 0 {
+            bail!("Connection closed before {} bytes arrived", size);
+        }
+
+        if closed {
+            bail!("Connection closed after {} of {} bytes", n, size);
+        }
+
+        received = n;
+        closed = is_read_closed(stream);
+
+        // Bytes sent before shutdown are all buffered by the time it is
+        // seen, so one more peek is final:
+        if closed {
+            continue;
+        }
+
+        if Instant::now() + backoff >= deadline {
+            return Err(Timeout { size, received }.into());
+        }
+
+        time::delay_for(backoff).await;
+        backoff = cmp::min(backoff * 2, BACKOFF_MAX);
+    }
+}
+
+/// Peeks (without consuming) until `is_complete` returns true for the bytes
+/// buffered so far, or until `max_length` bytes are buffered, whichever comes
+/// first, so caller has to check the result for completeness. Retries with
+/// the same backoff, and detects closed connections the same way as
+/// `peek_exact`.
+pub async fn peek_until<F>(
+    stream: &mut TcpStream,
+    max_length: usize,
+    deadline: Instant,
+    is_complete: F,
+) -> Fallible<Vec<u8>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let mut buf = vec![0; max_length];
+    let mut backoff = BACKOFF_MIN;
+    let mut received = 0;
+    let mut closed = false;
+
+    loop {
+        let n = match time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(Timeout {
+                    size: max_length,
+                    received,
+                }
+                .into())
+            }
+        };
+
+        if n == 0 {
+            bail!("Connection closed after {} bytes", received);
+        }
+
+        if n == max_length || is_complete(&buf[..n]) {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+
+        if closed {
+            bail!("Connection closed after {} bytes", n);
+        }
+
+        received = n;
+        closed = is_read_closed(stream);
+
+        if closed {
+            continue;
+        }
+
+        if Instant::now() + backoff >= deadline {
+            return Err(Timeout {
+                size: max_length,
+                received,
+            }
+            .into());
+        }
+
+        time::delay_for(backoff).await;
+        backoff = cmp::min(backoff * 2, BACKOFF_MAX);
+    }
+}
+
+/// Checks whether peer has shut down its side of the connection, so that no
+/// more bytes will arrive than are buffered already. Peek can't tell this as
+/// long as there are any.
+#[cfg(target_os = "linux")]
+fn is_read_closed(stream: &TcpStream) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+
+    let ready = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    ready > 0 && pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP) != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_read_closed(_stream: &TcpStream) -> bool {
+    false
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    pub fn chunks(data: &[u8], size: usize) -> Vec<Vec<u8>> {
+        data.chunks(size).map(<[u8]>::to_vec).collect()
+    }
+
+    /// Returns server end of a loopback connection, whose client end writes
+    /// `chunks` with `delay` before each one. Client end is then closed if
+    /// `close` is set, or kept open for the rest of the test otherwise.
+    pub async fn loopback(chunks: Vec<Vec<u8>>, delay: Duration, close: bool) -> TcpStream {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            for chunk in chunks {
+                time::delay_for(delay).await;
+                client.write_all(&chunk).await.unwrap();
+            }
+
+            if !close {
+                time::delay_for(Duration::from_secs(60)).await;
+            }
+        });
+
+        listener.accept().await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn peek_exact_waits_for_all_chunks() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut stream = loopback(chunks(&data, 5), Duration::from_millis(10), false).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        assert_eq!(
+            peek_exact(&mut stream, data.len(), deadline).await.unwrap(),
+            data
+        );
+
+        // Peeked bytes are left for splice:
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn peek_exact_fails_if_peer_closes() {
+        let mut stream = loopback(Vec::new(), Duration::from_millis(10), true).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let e = peek_exact(&mut stream, 16, deadline).await.unwrap_err();
+        assert!(e.downcast_ref::<Timeout>().is_none(), "{}", e);
+        assert!(Instant::now() < deadline);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn peek_exact_fails_if_peer_closes_midway() {
+        let data = [1, 2, 3, 4, 5, 6];
+        let mut stream = loopback(chunks(&data, 2), Duration::from_millis(10), true).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let e = peek_exact(&mut stream, 16, deadline).await.unwrap_err();
+        assert!(e.downcast_ref::<Timeout>().is_none(), "{}", e);
+        assert!(Instant::now() < deadline - Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn peek_exact_times_out() {
+        let data = [1, 2, 3, 4, 5, 6];
+        let mut stream = loopback(chunks(&data, 2), Duration::from_millis(10), false).await;
+        let deadline = Instant::now() + Duration::from_millis(300);
+
+        let e = peek_exact(&mut stream, 16, deadline).await.unwrap_err();
+        let timeout = e.downcast_ref::<Timeout>().unwrap();
+
+        assert_eq!((timeout.size, timeout.received), (16, data.len()));
+        assert!(Instant::now() < deadline + Duration::from_millis(100));
+    }
+}
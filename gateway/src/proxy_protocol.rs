@@ -0,0 +1,340 @@
+//! PROXY protocol headers, which carry original client address to upstream.
+//! See: https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+
+use failure::*;
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+use crate::ip;
+use crate::peek::peek_exact;
+use crate::rejection::Rejection;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+const V2_VERSION_LOCAL: u8 = 0x20;
+const V2_VERSION_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Original client (`src`) and server (`dst`) addresses of a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Addresses {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+fn to_ipv6(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    }
+}
+
+/// Unwraps IPv4-mapped address, so that IPv4 clients of dual-stack listener
+/// are sent as such.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(ip::canonical(addr.ip()), addr.port())
+}
+
+/// Human-readable header, e.g. `PROXY TCP4 1.2.3.4 5.6.7.8 1234 443\r\n`.
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = (canonical(src), canonical(dst));
+
+    let family = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => "TCP4",
+        (IpAddr::V6(_), IpAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src.ip(),
+        dst.ip(),
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+/// Binary header. Mixed address families are sent as IPv4-mapped IPv6.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = (canonical(src), canonical(dst));
+
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (to_ipv6(s), to_ipv6(d)),
+    };
+
+    let mut addrs = Vec::with_capacity(36);
+
+    let family = match (src_ip, dst_ip) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            addrs.extend_from_slice(&s.octets());
+            addrs.extend_from_slice(&d.octets());
+            V2_TCP4
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            addrs.extend_from_slice(&s.octets());
+            addrs.extend_from_slice(&d.octets());
+            V2_TCP6
+        }
+        _ => unreachable!(),
+    };
+
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+
+    let mut header = Vec::with_capacity(16 + addrs.len());
+
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_VERSION_PROXY);
+    header.push(family);
+    header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addrs);
+
+    header
+}
+
+pub fn encode(version: Version, addrs: Addresses) -> Vec<u8> {
+    match version {
+        Version::V1 => encode_v1(addrs.src, addrs.dst),
+        Version::V2 => encode_v2(addrs.src, addrs.dst),
+    }
+}
+
+fn malformed(reason: &'static str) -> Error {
+    Rejection::MalformedProxyHeader(reason).into()
+}
+
+fn parse_v1(line: &[u8]) -> Fallible<Option<Addresses>> {
+    let line = str::from_utf8(line).map_err(|_| malformed("v1 header is not UTF-8"))?;
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, dst, src_port, dst_port]
+        | ["PROXY", "TCP6", src, dst, src_port, dst_port] => {
+            let parse_addr = |ip: &str, port: &str| -> Fallible<SocketAddr> {
+                let ip: IpAddr = ip.parse().map_err(|_| malformed("v1 address is invalid"))?;
+                let port: u16 = port.parse().map_err(|_| malformed("v1 port is invalid"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+
+            Ok(Some(Addresses {
+                src: parse_addr(src, src_port)?,
+                dst: parse_addr(dst, dst_port)?,
+            }))
+        }
+        _ => Err(malformed("v1 header has unknown format")),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> Fallible<Option<Addresses>> {
+    let addrs = &header[V2_HEADER_LENGTH..];
+
+    match header[12] {
+        V2_VERSION_LOCAL => return Ok(None),
+        V2_VERSION_PROXY => {}
+        _ => return Err(malformed("v2 version or command is unknown")),
+    }
+
+    match header[13] {
+        V2_TCP4 if addrs.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addrs[at],
+                    addrs[at + 1],
+                    addrs[at + 2],
+                    addrs[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+
+            Ok(Some(Addresses {
+                src: SocketAddr::new(ip(0), port(8)),
+                dst: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        V2_TCP6 if addrs.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addrs[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+
+            Ok(Some(Addresses {
+                src: SocketAddr::new(ip(0), port(32)),
+                dst: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        V2_TCP4 | V2_TCP6 => Err(malformed("v2 address block is too short")),
+        // UNSPEC, UDP and UNIX families don't carry a usable TCP address:
+        _ => Ok(None),
+    }
+}
+
+/// Reads (consuming it from the stream) v1 or v2 header, sent by a trusted
+/// proxy in front of gateway. Returns `None` for LOCAL/UNKNOWN headers,
+/// meaning that the proxy itself is the client.
+pub async fn read_header(stream: &mut TcpStream, deadline: Instant) -> Fallible<Option<Addresses>> {
+    let buf = peek_exact(stream, V2_SIGNATURE.len(), deadline).await?;
+
+    let length = if buf == V2_SIGNATURE {
+        let buf = peek_exact(stream, V2_HEADER_LENGTH, deadline).await?;
+        V2_HEADER_LENGTH + usize::from(u16::from_be_bytes([buf[14], buf[15]]))
+    } else if buf.starts_with(V1_PREFIX) {
+        let mut length = buf.len();
+
+        loop {
+            let buf = peek_exact(stream, length, deadline).await?;
+
+            if buf.ends_with(b"\r\n") {
+                break length;
+            }
+
+            if length == V1_MAX_LENGTH {
+                return Err(malformed("v1 header is too long"));
+            }
+
+            length += 1;
+        }
+    } else {
+        return Err(malformed("header is missing"));
+    };
+
+    let mut header = vec![0; length];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(&V2_SIGNATURE) {
+        parse_v2(&header)
+    } else {
+        parse_v1(&header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    use crate::peek::tests::{chunks, loopback};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn round_trip(
+        src: &str,
+        dst: &str,
+    ) -> (Option<(SocketAddr, SocketAddr)>, (SocketAddr, SocketAddr)) {
+        let (src, dst) = (addr(src), addr(dst));
+
+        let v1 = parse_v1(&encode_v1(src, dst)).unwrap();
+        let v2 = parse_v2(&encode_v2(src, dst)).unwrap().unwrap();
+
+        (v1.map(|addrs| (addrs.src, addrs.dst)), (v2.src, v2.dst))
+    }
+
+    #[test]
+    fn round_trips_ipv4() {
+        let expected = (addr("192.0.2.1:1234"), addr("198.51.100.1:443"));
+        let (v1, v2) = round_trip("192.0.2.1:1234", "198.51.100.1:443");
+
+        assert_eq!(v1, Some(expected));
+        assert_eq!(v2, expected);
+    }
+
+    #[test]
+    fn round_trips_ipv6() {
+        let expected = (addr("[2001:db8::1]:1234"), addr("[2001:db8::2]:443"));
+        let (v1, v2) = round_trip("[2001:db8::1]:1234", "[2001:db8::2]:443");
+
+        assert_eq!(v1, Some(expected));
+        assert_eq!(v2, expected);
+    }
+
+    #[test]
+    fn unwraps_ipv4_mapped_addresses() {
+        let expected = (addr("192.0.2.1:1234"), addr("198.51.100.1:443"));
+        let (v1, v2) = round_trip("[::ffff:192.0.2.1]:1234", "[::ffff:198.51.100.1]:443");
+
+        assert_eq!(v1, Some(expected));
+        assert_eq!(v2, expected);
+
+        assert!(
+            encode_v1(expected.0, addr("[::ffff:198.51.100.1]:443")).starts_with(b"PROXY TCP4 ")
+        );
+        assert_eq!(
+            encode_v2(expected.0, addr("[::ffff:198.51.100.1]:443"))[13],
+            V2_TCP4
+        );
+    }
+
+    #[test]
+    fn sends_mixed_families_as_unknown_or_ipv6() {
+        let (v1, v2) = round_trip("[2001:db8::1]:1234", "198.51.100.1:443");
+
+        assert_eq!(v1, None);
+        assert_eq!(
+            v2,
+            (
+                addr("[2001:db8::1]:1234"),
+                addr("[::ffff:198.51.100.1]:443")
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_v2_address_block() {
+        let mut header = encode_v2(addr("192.0.2.1:1234"), addr("198.51.100.1:443"));
+        header.truncate(V2_HEADER_LENGTH + 8);
+
+        assert!(parse_v2(&header).is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_v2_header() {
+        let header = encode_v2(addr("192.0.2.1:1234"), addr("198.51.100.1:443"));
+        let mut stream = loopback(
+            chunks(&header[..V2_HEADER_LENGTH + 4], 8),
+            Duration::from_millis(5),
+            false,
+        )
+        .await;
+        let deadline = Instant::now() + Duration::from_millis(300);
+
+        assert!(read_header(&mut stream, deadline).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_over_long_v1_line() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(V1_MAX_LENGTH + 16, b'1');
+        line.extend_from_slice(b"\r\n");
+
+        let mut stream = loopback(vec![line], Duration::from_millis(5), false).await;
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        let e = read_header(&mut stream, deadline).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<Rejection>().map(Rejection::reason),
+            Some("malformed_proxy_header")
+        );
+    }
+}
@@ -0,0 +1,604 @@
+//! Decryption of client QUIC Initial packets, which are protected with keys
+//! derived from client's destination connection ID, so that ClientHello can
+//! be extracted from their CRYPTO frames.
+//!
+//! See: https://tools.ietf.org/html/rfc9001#section-5
+
+use failure::*;
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use ring::hkdf::{self, KeyType, Prk, Salt, HKDF_SHA256};
+use tokio::time::Instant;
+
+const FORM_LONG: u8 = 0x80;
+const MAX_CID_LENGTH: usize = 20;
+const SAMPLE_LENGTH: usize = 16;
+const HANDSHAKE_HEADER_LENGTH: usize = 4;
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+const FRAME_CONNECTION_CLOSE: u64 = 0x1c;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Malformed QUIC Initial: {}", _0)]
+pub struct Malformed(pub &'static str);
+
+/// Version-specific constants for Initial packet protection.
+struct Version {
+    number: u32,
+    initial_type: u8,
+    salt: [u8; 20],
+    key_label: &'static [u8],
+    iv_label: &'static [u8],
+    hp_label: &'static [u8],
+}
+
+const VERSIONS: &[Version] = &[
+    // RFC 9001
+    Version {
+        number: 0x0000_0001,
+        initial_type: 0,
+        salt: [
+            0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+            0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+        ],
+        key_label: b"quic key",
+        iv_label: b"quic iv",
+        hp_label: b"quic hp",
+    },
+    // RFC 9369
+    Version {
+        number: 0x6b33_43cf,
+        initial_type: 1,
+        salt: [
+            0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+            0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+        ],
+        key_label: b"quicv2 key",
+        iv_label: b"quicv2 iv",
+        hp_label: b"quicv2 hp",
+    },
+    // draft-ietf-quic-tls-29, still sent by some clients
+    Version {
+        number: 0xff00_001d,
+        initial_type: 0,
+        salt: [
+            0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61,
+            0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99,
+        ],
+        key_label: b"quic key",
+        iv_label: b"quic iv",
+        hp_label: b"quic hp",
+    },
+];
+
+/// Output length for HKDF-Expand-Label.
+struct Length(usize);
+
+impl KeyType for Length {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label with empty context. Output borrows label, so it is
+/// passed to `f` to be converted into a key.
+/// See: https://tools.ietf.org/html/rfc8446#section-7.1
+fn expand_label<L, T, F>(prk: &Prk, label: &[u8], length: L, f: F) -> Fallible<T>
+where
+    L: KeyType,
+    F: FnOnce(hkdf::Okm<'_, L>) -> T,
+{
+    let output_length = (length.len() as u16).to_be_bytes();
+    let label_length = [(b"tls13 ".len() + label.len()) as u8];
+    let info: &[&[u8]] = &[&output_length, &label_length, b"tls13 ", label, &[0]];
+
+    Ok(f(prk.expand(info, length)?))
+}
+
+/// Client Initial packet protection keys.
+struct Keys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    hp: HeaderProtectionKey,
+}
+
+impl Keys {
+    fn new(version: &Version, dcid: &[u8]) -> Fallible<Self> {
+        let initial_secret = Salt::new(HKDF_SHA256, &version.salt).extract(dcid);
+        let client_secret = expand_label(&initial_secret, b"client in", HKDF_SHA256, Prk::from)?;
+
+        let key = expand_label(
+            &client_secret,
+            version.key_label,
+            &AES_128_GCM,
+            UnboundKey::from,
+        )?;
+
+        let mut iv = [0; 12];
+        expand_label(&client_secret, version.iv_label, Length(iv.len()), |okm| {
+            okm.fill(&mut iv)
+        })??;
+
+        let hp = expand_label(
+            &client_secret,
+            version.hp_label,
+            &AES_128,
+            HeaderProtectionKey::from,
+        )?;
+
+        Ok(Keys {
+            key: LessSafeKey::new(key),
+            iv,
+            hp,
+        })
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize, what: &'static str) -> Result<&'a [u8], Malformed> {
+        if self.buf.len().saturating_sub(self.offset) < length {
+            return Err(Malformed(what));
+        }
+
+        self.offset += length;
+        Ok(&self.buf[self.offset - length..self.offset])
+    }
+
+    fn u8(&mut self, what: &'static str) -> Result<u8, Malformed> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    /// Reads variable-length integer.
+    /// See: https://tools.ietf.org/html/rfc9000#section-16
+    fn varint(&mut self, what: &'static str) -> Result<u64, Malformed> {
+        let first = self.u8(what)?;
+        let rest = self.take((1 << (first >> 6)) - 1, what)?;
+
+        Ok(rest
+            .iter()
+            .fold(u64::from(first & 0x3f), |x, &b| x << 8 | u64::from(b)))
+    }
+
+    fn vec8(&mut self, what: &'static str) -> Result<&'a [u8], Malformed> {
+        let length = self.u8(what)?;
+        self.take(usize::from(length), what)
+    }
+}
+
+pub fn is_long_header(datagram: &[u8]) -> bool {
+    datagram.first().map_or(false, |b| b & FORM_LONG != 0)
+}
+
+/// Returns destination connection ID of a datagram that starts with a long
+/// header packet, or of a short header packet for a given ID length.
+pub fn destination_cid(datagram: &[u8], short_length: usize) -> Option<&[u8]> {
+    match datagram.first()? & FORM_LONG {
+        0 => datagram.get(1..1 + short_length),
+        _ => Reader {
+            buf: datagram,
+            offset: 5,
+        }
+        .vec8("")
+        .ok(),
+    }
+}
+
+/// Returns source connection ID of a datagram that starts with a long header
+/// packet.
+pub fn source_cid(datagram: &[u8]) -> Option<&[u8]> {
+    if datagram.first()? & FORM_LONG == 0 {
+        return None;
+    }
+
+    let mut rd = Reader {
+        buf: datagram,
+        offset: 5,
+    };
+
+    rd.vec8("").ok()?;
+    rd.vec8("").ok()
+}
+
+/// CRYPTO frame data from client Initial packets in a datagram.
+#[derive(Debug)]
+pub struct Initial {
+    pub dcid: Vec<u8>,
+    pub crypto: Vec<(u64, Vec<u8>)>,
+}
+
+/// Removes header protection and decrypts Initial packet, returning its
+/// plaintext payload. Truncated packet number is taken as is, since client
+/// has not received any acknowledgements for Initial packets yet.
+fn decrypt(keys: &Keys, packet: &mut [u8], pn_offset: usize) -> Fallible<Vec<u8>> {
+    let sample = packet
+        .get(pn_offset + 4..pn_offset + 4 + SAMPLE_LENGTH)
+        .ok_or(Malformed("packet is too short to sample"))?;
+    let mask = keys.hp.new_mask(sample)?;
+
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_length = usize::from(packet[0] & 0x03) + 1;
+
+    let mut nonce = keys.iv;
+    for i in 0..pn_length {
+        packet[pn_offset + i] ^= mask[1 + i];
+        nonce[12 - pn_length + i] ^= packet[pn_offset + i];
+    }
+
+    let (header, payload) = packet.split_at_mut(pn_offset + pn_length);
+    let plaintext = keys.key.open_in_place(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&*header),
+        payload,
+    )?;
+
+    Ok(plaintext.to_vec())
+}
+
+/// Collects CRYPTO frames from Initial packet payload, checking that it only
+/// contains frames allowed in Initial packets.
+fn crypto_frames(payload: &[u8], crypto: &mut Vec<(u64, Vec<u8>)>) -> Result<(), Malformed> {
+    let mut rd = Reader {
+        buf: payload,
+        offset: 0,
+    };
+
+    while rd.offset < payload.len() {
+        match rd.varint("failed to read frame type")? {
+            FRAME_PADDING | FRAME_PING => (),
+            typ @ FRAME_ACK | typ @ FRAME_ACK_ECN => {
+                rd.varint("failed to read largest acknowledged")?;
+                rd.varint("failed to read ACK delay")?;
+                let ranges = rd.varint("failed to read ACK range count")?;
+                rd.varint("failed to read first ACK range")?;
+
+                for _ in 0..ranges {
+                    rd.varint("failed to read ACK range gap")?;
+                    rd.varint("failed to read ACK range length")?;
+                }
+
+                if typ == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        rd.varint("failed to read ECN count")?;
+                    }
+                }
+            }
+            FRAME_CRYPTO => {
+                let offset = rd.varint("failed to read CRYPTO offset")?;
+                let length = rd.varint("failed to read CRYPTO length")?;
+                let data = rd.take(length as usize, "CRYPTO frame is truncated")?;
+
+                crypto.push((offset, data.to_vec()));
+            }
+            FRAME_CONNECTION_CLOSE => return Ok(()),
+            _ => return Err(Malformed("frame is not allowed in Initial packet")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses client Initial packets coalesced into a datagram, stopping at the
+/// first packet of another type. Returns `None` if datagram doesn't start
+/// with Initial packet of a known version.
+pub fn parse_initial(datagram: &[u8]) -> Fallible<Option<Initial>> {
+    let mut initial: Option<Initial> = None;
+    let mut offset = 0;
+
+    while offset < datagram.len() && datagram[offset] & FORM_LONG != 0 {
+        let mut rd = Reader {
+            buf: datagram,
+            offset: offset + 1,
+        };
+
+        let number = rd.take(4, "failed to read version")?;
+        let number = u32::from_be_bytes([number[0], number[1], number[2], number[3]]);
+
+        let version = match VERSIONS.iter().find(|v| v.number == number) {
+            Some(version) => version,
+            None => break,
+        };
+
+        if (datagram[offset] >> 4) & 0x03 != version.initial_type {
+            break;
+        }
+
+        let dcid = rd.vec8("failed to read destination connection ID")?;
+        let scid = rd.vec8("failed to read source connection ID")?;
+
+        if dcid.len() > MAX_CID_LENGTH || scid.len() > MAX_CID_LENGTH {
+            return Err(Malformed("connection ID is too long").into());
+        }
+
+        let token_length = rd.varint("failed to read token length")?;
+        rd.take(token_length as usize, "failed to read token")?;
+        let length = rd.varint("failed to read packet length")? as usize;
+        let pn_offset = rd.offset;
+
+        let mut packet = datagram
+            .get(offset..pn_offset + length)
+            .ok_or(Malformed("packet is truncated"))?
+            .to_vec();
+
+        let keys = Keys::new(version, dcid)?;
+        let payload = decrypt(&keys, &mut packet, pn_offset - offset)
+            .map_err(|_| Malformed("failed to decrypt packet"))?;
+
+        let initial = initial.get_or_insert_with(|| Initial {
+            dcid: dcid.to_vec(),
+            crypto: Vec::new(),
+        });
+
+        crypto_frames(&payload, &mut initial.crypto)?;
+        offset = pn_offset + length;
+    }
+
+    Ok(initial)
+}
+
+/// Reassembles ClientHello from CRYPTO frames, which may span several Initial
+/// packets and datagrams, and come out of order. Datagrams are kept to be
+/// forwarded once upstream is known.
+pub struct Assembler {
+    pub started: Instant,
+    pub datagrams: Vec<Vec<u8>>,
+    fragments: Vec<(u64, Vec<u8>)>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler {
+            started: Instant::now(),
+            datagrams: Vec::new(),
+            fragments: Vec::new(),
+        }
+    }
+}
+
+impl Assembler {
+    /// Total size of datagrams kept.
+    pub fn size(&self) -> usize {
+        self.datagrams.iter().map(Vec::len).sum()
+    }
+
+    pub fn push(&mut self, datagram: Vec<u8>, initial: Initial) -> Result<(), Malformed> {
+        if self.datagrams.len() == MAX_PENDING_DATAGRAMS {
+            return Err(Malformed("ClientHello spans too many datagrams"));
+        }
+
+        self.datagrams.push(datagram);
+        self.fragments.extend(initial.crypto);
+        Ok(())
+    }
+
+    /// Returns complete ClientHello handshake message, once all of it has
+    /// been received. Messages longer than `max_length` are rejected.
+    pub fn client_hello(&mut self, max_length: usize) -> Result<Option<Vec<u8>>, Malformed> {
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+
+        let mut handshake = Vec::new();
+
+        for (offset, data) in &self.fragments {
+            let offset = *offset as usize;
+
+            if offset > handshake.len() {
+                break;
+            }
+
+            if offset + data.len() > handshake.len() {
+                handshake.extend_from_slice(&data[handshake.len() - offset..]);
+            }
+        }
+
+        if handshake.len() < HANDSHAKE_HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        let length = HANDSHAKE_HEADER_LENGTH
+            + ((usize::from(handshake[1]) << 16)
+                | (usize::from(handshake[2]) << 8)
+                | usize::from(handshake[3]));
+
+        if length > max_length {
+            return Err(Malformed("ClientHello is too long"));
+        }
+
+        if handshake.len() < length {
+            return Ok(None);
+        }
+
+        handshake.truncate(length);
+        Ok(Some(handshake))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from RFC 9001 Appendix A and RFC 9369 Appendix A
+    const DCID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+    const CLIENT_HELLO: &str = "
+    010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e86804fe3a47
+    f06a2b69484c00000413011302010000c000000010000e00000b6578616d706c
+    652e636f6dff01000100000a00080006001d0017001800100007000504616c70
+    6e000500050100000000003300260024001d00209370b2c9caa47fbabaf4559f
+    edba753de171fa71f50f1ce15d43e994ec74d748002b0003020304000d001000
+    0e0403050306030203080408050806002d00020101001c000240010039003204
+    08ffffffffffffffff05048000ffff07048000ffff0801100104800075300901
+    100f088394c8f03e51570806048000ffff
+    ";
+
+    const V1_INITIAL: &str = "
+    c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11
+    d242b123dc9bd8bab936b47d92ec356c0bab7df5976d27cd449f63300099f399
+    1c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c
+    8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df6212
+    30c83711b39343fa028cea7f7fb5ff89eac2308249a02252155e2347b63d58c5
+    457afd84d05dfffdb20392844ae812154682e9cf012f9021a6f0be17ddd0c208
+    4dce25ff9b06cde535d0f920a2db1bf362c23e596d11a4f5a6cf3948838a3aec
+    4e15daf8500a6ef69ec4e3feb6b1d98e610ac8b7ec3faf6ad760b7bad1db4ba3
+    485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db
+    059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c
+    7b4378e846d29f37ed7b4ea9ec5d82e7961b7f25a9323851f681d582363aa5f8
+    9937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556
+    be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c74
+    68449a13d8e3b95811a198f3491de3e7fe942b330407abf82a4ed7c1b311663a
+    c69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00
+    f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632
+    291d6a418211cc2962e20fe47feb3edf330f2c603a9d48c0fcb5699dbfe58964
+    25c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd
+    14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ff
+    ef132eef2fa09346aee33c28eb130ff28f5b766953334113211996d20011a198
+    e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd
+    c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73
+    203a4a13e96f5432ec0fd4a1ee65accdd5e3904df54c1da510b0ff20dcc0c77f
+    cb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e
+    fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03ade
+    a2e1fbc5aa463d08ca19896d2bf59a071b851e6c239052172f296bfb5e724047
+    90a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2
+    162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f4
+    40591f355e12d439ff150aab7613499dbd49adabc8676eef023b15b65bfc5ca0
+    6948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e
+    8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0
+    be79e2fb8f5d5fbbe2e30ecadd220723c8c0aea8078cdfcb3868263ff8f09400
+    54da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab
+    760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9
+    f96f3ca9ec1dde434da7d2d392b905ddf3d1f9af93d1af5950bd493f5aa731b4
+    056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064
+    7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f9474241
+    e221af44860018ab0856972e194cd934
+    ";
+
+    const V2_INITIAL: &str = "
+    d76b3343cf088394c8f03e5157080000449ea0c95e82ffe67b6abcdb4298b485
+    dd04de806071bf03dceebfa162e75d6c96058bdbfb127cdfcbf903388e99ad04
+    9f9a3dd4425ae4d0992cfff18ecf0fdb5a842d09747052f17ac2053d21f57c5d
+    250f2c4f0e0202b70785b7946e992e58a59ac52dea6774d4f03b55545243cf1a
+    12834e3f249a78d395e0d18f4d766004f1a2674802a747eaa901c3f10cda5500
+    cb9122faa9f1df66c392079a1b40f0de1c6054196a11cbea40afb6ef5253cd68
+    18f6625efce3b6def6ba7e4b37a40f7732e093daa7d52190935b8da58976ff33
+    12ae50b187c1433c0f028edcc4c2838b6a9bfc226ca4b4530e7a4ccee1bfa2a3
+    d396ae5a3fb512384b2fdd851f784a65e03f2c4fbe11a53c7777c023462239dd
+    6f7521a3f6c7d5dd3ec9b3f233773d4b46d23cc375eb198c63301c21801f6520
+    bcfb7966fc49b393f0061d974a2706df8c4a9449f11d7f3d2dcbb90c6b877045
+    636e7c0c0fe4eb0f697545460c806910d2c355f1d253bc9d2452aaa549e27a1f
+    ac7cf4ed77f322e8fa894b6a83810a34b361901751a6f5eb65a0326e07de7c12
+    16ccce2d0193f958bb3850a833f7ae432b65bc5a53975c155aa4bcb4f7b2c4e5
+    4df16efaf6ddea94e2c50b4cd1dfe06017e0e9d02900cffe1935e0491d77ffb4
+    fdf85290fdd893d577b1131a610ef6a5c32b2ee0293617a37cbb08b847741c3b
+    8017c25ca9052ca1079d8b78aebd47876d330a30f6a8c6d61dd1ab5589329de7
+    14d19d61370f8149748c72f132f0fc99f34d766c6938597040d8f9e2bb522ff9
+    9c63a344d6a2ae8aa8e51b7b90a4a806105fcbca31506c446151adfeceb51b91
+    abfe43960977c87471cf9ad4074d30e10d6a7f03c63bd5d4317f68ff325ba3bd
+    80bf4dc8b52a0ba031758022eb025cdd770b44d6d6cf0670f4e990b22347a7db
+    848265e3e5eb72dfe8299ad7481a408322cac55786e52f633b2fb6b614eaed18
+    d703dd84045a274ae8bfa73379661388d6991fe39b0d93debb41700b41f90a15
+    c4d526250235ddcd6776fc77bc97e7a417ebcb31600d01e57f32162a8560cacc
+    7e27a096d37a1a86952ec71bd89a3e9a30a2a26162984d7740f81193e8238e61
+    f6b5b984d4d3dfa033c1bb7e4f0037febf406d91c0dccf32acf423cfa1e70710
+    10d3f270121b493ce85054ef58bada42310138fe081adb04e2bd901f2f13458b
+    3d6758158197107c14ebb193230cd1157380aa79cae1374a7c1e5bbcb80ee23e
+    06ebfde206bfb0fcbc0edc4ebec309661bdd908d532eb0c6adc38b7ca7331dce
+    8dfce39ab71e7c32d318d136b6100671a1ae6a6600e3899f31f0eed19e3417d1
+    34b90c9058f8632c798d4490da4987307cba922d61c39805d072b589bd52fdf1
+    e86215c2d54e6670e07383a27bbffb5addf47d66aa85a0c6f9f32e59d85a44dd
+    5d3b22dc2be80919b490437ae4f36a0ae55edf1d0b5cb4e9a3ecabee93dfc6e3
+    8d209d0fa6536d27a5d6fbb17641cde27525d61093f1b28072d111b2b4ae5f89
+    d5974ee12e5cf7d5da4d6a31123041f33e61407e76cffcdcfd7e19ba58cf4b53
+    6f4c4938ae79324dc402894b44faf8afbab35282ab659d13c93f70412e85cb19
+    9a37ddec600545473cfb5a05e08d0b209973b2172b4d21fb69745a262ccde96b
+    a18b2faa745b6fe189cf772a9f84cbfc
+    ";
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s
+            .chars()
+            .filter_map(|c| c.to_digit(16))
+            .map(|d| d as u8)
+            .collect();
+
+        digits.chunks(2).map(|d| d[0] << 4 | d[1]).collect()
+    }
+
+    fn version(number: u32) -> &'static Version {
+        VERSIONS.iter().find(|v| v.number == number).unwrap()
+    }
+
+    fn check_keys(version: &Version, key: &str, iv: &str, hp: &str) {
+        let keys = Keys::new(version, DCID).unwrap();
+        assert_eq!(keys.iv.to_vec(), hex(iv));
+
+        let expected = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &hex(key)).unwrap());
+        let seal = |key: &LessSafeKey| {
+            let nonce = Nonce::assume_unique_for_key([0; 12]);
+            let tag = key
+                .seal_in_place_separate_tag(nonce, Aad::empty(), &mut [0; 16])
+                .unwrap();
+            tag.as_ref().to_vec()
+        };
+        assert_eq!(seal(&keys.key), seal(&expected));
+
+        let expected = HeaderProtectionKey::new(&AES_128, &hex(hp)).unwrap();
+        let sample = [0x5a; SAMPLE_LENGTH];
+        assert_eq!(
+            keys.hp.new_mask(&sample).unwrap(),
+            expected.new_mask(&sample).unwrap()
+        );
+    }
+
+    fn check_initial(datagram: &str) {
+        let initial = parse_initial(&hex(datagram)).unwrap().unwrap();
+
+        assert_eq!(initial.dcid, DCID);
+        assert_eq!(initial.crypto, vec![(0, hex(CLIENT_HELLO))]);
+    }
+
+    #[test]
+    fn derives_v1_client_keys() {
+        check_keys(
+            version(0x0000_0001),
+            "1f369613dd76d5467730efcbe3b1a22d",
+            "fa044b2f42a3fd3b46fb255c",
+            "9f50449e04a0e810283a1e9933adedd2",
+        );
+    }
+
+    #[test]
+    fn derives_v2_client_keys() {
+        check_keys(
+            version(0x6b33_43cf),
+            "8b1a0bc121284290a29e0971b5cd045d",
+            "91f73e2351d8fa91660e909f",
+            "45b95e15235d6f45a6b19cbcb0294ba9",
+        );
+    }
+
+    #[test]
+    fn parses_v1_client_initial() {
+        check_initial(V1_INITIAL);
+    }
+
+    #[test]
+    fn parses_v2_client_initial() {
+        check_initial(V2_INITIAL);
+    }
+
+    #[test]
+    fn rejects_tampered_client_initial() {
+        let mut datagram = hex(V1_INITIAL);
+        datagram[100] ^= 1;
+
+        assert!(parse_initial(&datagram).is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use ipnet::IpNet;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+use crate::ip;
+
+/// How often buckets that have refilled completely are dropped, so that
+/// memory use is bounded by recently active networks.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct State {
+    buckets: HashMap<IpNet, Bucket>,
+    cleaned_up: Instant,
+}
+
+/// Token bucket limiter of new connections, keyed by source network (IPv6
+/// addresses are aggregated by /64 by default, since that is what a single
+/// host usually gets).
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                cleaned_up: Instant::now(),
+            }),
+        }
+    }
+
+    fn network(&self, ip: IpAddr) -> IpNet {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+
+        IpNet::new(ip, prefix).unwrap().trunc()
+    }
+
+    /// Takes a token for a new connection from source IP. Returns `false` if
+    /// the bucket is empty. Allowlisted networks are never limited.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let ip = ip::canonical(ip);
+
+        if self.config.allowlist.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+
+        let now = Instant::now();
+        let burst = f64::from(self.config.burst);
+        let rate = self.config.rate;
+        let mut state = self.state.lock().unwrap();
+
+        if now.duration_since(state.cleaned_up) >= CLEANUP_INTERVAL {
+            state.buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * rate < burst
+            });
+
+            state.cleaned_up = now;
+        }
+
+        let bucket = state.buckets.entry(self.network(ip)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
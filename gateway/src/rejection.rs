@@ -0,0 +1,105 @@
+use failure::*;
+
+/// TLS alert descriptions sent on rejection.
+/// See: https://tools.ietf.org/html/rfc8446#section-6
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alert {
+    HandshakeFailure = 40,
+    AccessDenied = 49,
+    InternalError = 80,
+    UnrecognizedName = 112,
+}
+
+/// Reason for refusing to dispatch a connection before splice starts.
+#[derive(Debug, Fail)]
+pub enum Rejection {
+    #[fail(display = "Malformed PROXY protocol header: {}", _0)]
+    MalformedProxyHeader(&'static str),
+    #[fail(display = "Content type is not Handshake")]
+    NotHandshake,
+    #[fail(display = "Malformed ClientHello: {}", _0)]
+    MalformedClientHello(&'static str),
+    #[fail(display = "ClientHello is longer than max of {} bytes", _0)]
+    ClientHelloTooLong(usize),
+    #[fail(display = "SNI is missing")]
+    MissingSni,
+    #[fail(display = "Malformed HTTP request: {}", _0)]
+    MalformedHttpRequest(&'static str),
+    #[fail(display = "Host header is missing")]
+    MissingHost,
+    #[fail(display = "Hostname {} does not match any allowed suffix", _0)]
+    DisallowedHostname(String),
+    #[fail(display = "Hostname {} is not a valid agent hostname", _0)]
+    InvalidAgentHostname(String),
+    #[fail(display = "Hostname {} is blocked", _0)]
+    Blocked(String),
+    #[fail(display = "Too many concurrent connections to {}", _0)]
+    HostnameLimit(String),
+    #[fail(display = "{} does not resolve", _0)]
+    Unresolvable(String),
+    #[fail(display = "Failed to connect to {}: {}", _0, _1)]
+    UpstreamUnreachable(String, String),
+}
+
+impl Rejection {
+    /// Short machine-readable reason, for logs and metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::MalformedProxyHeader(_) => "malformed_proxy_header",
+            Rejection::NotHandshake => "not_handshake",
+            Rejection::MalformedClientHello(_) => "malformed_client_hello",
+            Rejection::ClientHelloTooLong(_) => "client_hello_too_long",
+            Rejection::MissingSni => "missing_sni",
+            Rejection::MalformedHttpRequest(_) => "malformed_http_request",
+            Rejection::MissingHost => "missing_host",
+            Rejection::DisallowedHostname(_) => "disallowed_hostname",
+            Rejection::InvalidAgentHostname(_) => "invalid_agent_hostname",
+            Rejection::Blocked(_) => "blocked",
+            Rejection::HostnameLimit(_) => "hostname_limit",
+            Rejection::Unresolvable(_) => "unresolvable",
+            Rejection::UpstreamUnreachable(..) => "upstream_unreachable",
+        }
+    }
+
+    /// Alert to send to client before closing connection. Clients that
+    /// didn't start a TLS handshake don't get one.
+    pub fn alert(&self) -> Option<Alert> {
+        match self {
+            Rejection::MalformedProxyHeader(_)
+            | Rejection::NotHandshake
+            | Rejection::MalformedHttpRequest(_)
+            | Rejection::MissingHost => None,
+            Rejection::MalformedClientHello(_)
+            | Rejection::ClientHelloTooLong(_)
+            | Rejection::MissingSni => Some(Alert::HandshakeFailure),
+            Rejection::DisallowedHostname(_)
+            | Rejection::InvalidAgentHostname(_)
+            | Rejection::Unresolvable(_) => Some(Alert::UnrecognizedName),
+            Rejection::Blocked(_) => Some(Alert::AccessDenied),
+            Rejection::HostnameLimit(_) | Rejection::UpstreamUnreachable(..) => {
+                Some(Alert::InternalError)
+            }
+        }
+    }
+
+    /// Status to respond with to plaintext HTTP clients. Rejections that
+    /// only happen during TLS handshake don't have one.
+    pub fn http_status(&self) -> Option<&'static str> {
+        match self {
+            Rejection::MalformedProxyHeader(_)
+            | Rejection::NotHandshake
+            | Rejection::MalformedClientHello(_)
+            | Rejection::ClientHelloTooLong(_)
+            | Rejection::MissingSni => None,
+            Rejection::MalformedHttpRequest(_) | Rejection::MissingHost => Some("400 Bad Request"),
+            Rejection::DisallowedHostname(_) | Rejection::InvalidAgentHostname(_) => {
+                Some("421 Misdirected Request")
+            }
+            Rejection::Blocked(_) => Some("403 Forbidden"),
+            Rejection::HostnameLimit(_) => Some("503 Service Unavailable"),
+            Rejection::Unresolvable(_) | Rejection::UpstreamUnreachable(..) => {
+                Some("502 Bad Gateway")
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use failure::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Duration};
+use tracing::*;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::config::ReloadableFileConfig;
+
+/// Value that is loaded from a file.
+pub trait Load: Sized {
+    fn load(path: &Path) -> Fallible<Self>;
+}
+
+/// Value loaded from a file that can be atomically replaced at runtime.
+/// Readers get a snapshot that stays valid for as long as they hold it, so
+/// reload never affects connections that are already in flight.
+pub struct Reloadable<T> {
+    path: PathBuf,
+    interval: Duration,
+    current: RwLock<Arc<T>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl<T: Load> Reloadable<T> {
+    /// Loads file from config, if its path is set.
+    pub fn from_config(config: &ReloadableFileConfig) -> Fallible<Option<Self>> {
+        match &config.path {
+            Some(path) => Ok(Some(Self::load(path.clone(), config.reload_interval())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load(path: PathBuf, interval: Duration) -> Fallible<Self> {
+        let modified = modified(&path);
+        let value =
+            T::load(&path).with_context(|e| format!("Failed to load {}: {}", path.display(), e))?;
+
+        Ok(Reloadable {
+            path,
+            interval,
+            current: RwLock::new(Arc::new(value)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reloads file, keeping current value if the new one fails to load.
+    pub fn reload(&self) {
+        *self.modified.lock().unwrap() = modified(&self.path);
+
+        match T::load(&self.path) {
+            Ok(value) => {
+                *self.current.write().unwrap() = Arc::new(value);
+                info!("Reloaded {}", self.path.display());
+            }
+            Err(e) => error!(
+                "Failed to reload {}, keeping previous version: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+
+    fn is_modified(&self) -> bool {
+        modified(&self.path) != *self.modified.lock().unwrap()
+    }
+
+    /// Reloads file on SIGHUP, and whenever its modification time changes
+    /// (checked every reload interval).
+    pub async fn watch(&self) -> Fallible<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    debug!("Received SIGHUP");
+                    self.reload();
+                }
+                _ = time::delay_for(self.interval) => {
+                    if self.is_modified() {
+                        self.reload();
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! DNS-over-HTTPS (RFC 8484) client with in-process cache that respects
+//! record TTLs and caches negative answers.
+
+use failure::*;
+use futures::future;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use tokio::time::{self, Duration, Instant};
+use tracing::*;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::config::ResolverConfig;
+use crate::dns;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} does not resolve", _0)]
+pub struct NotFound(pub String);
+
+enum Entry {
+    Positive {
+        addrs: Vec<IpAddr>,
+        expires: Instant,
+    },
+    Negative {
+        expires: Instant,
+    },
+}
+
+impl Entry {
+    fn expires(&self) -> Instant {
+        match self {
+            Entry::Positive { expires, .. } | Entry::Negative { expires } => *expires,
+        }
+    }
+}
+
+pub struct Resolver {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: String,
+    config: ResolverConfig,
+    cache: Mutex<HashMap<String, Entry>>,
+}
+
+impl Resolver {
+    pub fn new(url: String, config: ResolverConfig) -> Self {
+        Resolver {
+            client: Client::builder().build(HttpsConnector::new()),
+            url,
+            config,
+            cache: Default::default(),
+        }
+    }
+
+    fn cached(&self, hostname: &str) -> Option<Fallible<Vec<IpAddr>>> {
+        let cache = self.cache.lock().unwrap();
+
+        match cache.get(hostname) {
+            Some(entry) if entry.expires() > Instant::now() => match entry {
+                Entry::Positive { addrs, .. } => Some(Ok(addrs.clone())),
+                Entry::Negative { .. } => Some(Err(NotFound(hostname.into()).into())),
+            },
+            _ => None,
+        }
+    }
+
+    fn insert(&self, hostname: &str, entry: Entry) {
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= self.config.cache_size {
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.expires() > now);
+
+            if cache.len() >= self.config.cache_size {
+                cache.clear();
+            }
+        }
+
+        cache.insert(hostname.into(), entry);
+    }
+
+    async fn query(&self, hostname: &str, qtype: u16) -> Fallible<dns::Response> {
+        let message = dns::encode_query(hostname, qtype)?;
+        let uri = format!(
+            "{}?dns={}",
+            self.url,
+            base64::encode_config(&message, base64::URL_SAFE_NO_PAD)
+        );
+
+        let request = Request::get(uri)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::empty())?;
+
+        let response = time::timeout(self.config.timeout(), self.client.request(request)).await??;
+
+        if !response.status().is_success() {
+            bail!("DoH server responded with {}", response.status());
+        }
+
+        match response.headers().get(CONTENT_TYPE) {
+            Some(value) if value == DNS_MESSAGE => {}
+            _ => bail!("DoH server responded with unexpected content type"),
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        dns::decode_response(&body)
+    }
+
+    /// Resolves hostname to a list of addresses. Both positive and negative
+    /// answers are cached.
+    pub async fn resolve(&self, hostname: &str) -> Fallible<Vec<IpAddr>> {
+        let hostname = hostname.to_ascii_lowercase();
+
+        if let Some(result) = self.cached(&hostname) {
+            debug!("Resolved {} from cache", hostname);
+            return result;
+        }
+
+        let mut queries = vec![self.query(&hostname, dns::TYPE_A)];
+
+        if self.config.ipv6 {
+            queries.push(self.query(&hostname, dns::TYPE_AAAA));
+        }
+
+        let mut addrs = Vec::new();
+        let mut ttl = None;
+        let mut negative_ttl = None;
+
+        for response in future::try_join_all(queries).await? {
+            match response.rcode {
+                dns::RCODE_NOERROR | dns::RCODE_NXDOMAIN => {}
+                rcode => bail!("DoH server responded with RCODE {}", rcode),
+            }
+
+            for (addr, addr_ttl) in response.addrs {
+                addrs.push(addr);
+                ttl = Some(ttl.unwrap_or(addr_ttl).min(addr_ttl));
+            }
+
+            negative_ttl = negative_ttl.or(response.negative_ttl);
+        }
+
+        if addrs.is_empty() {
+            let ttl = negative_ttl.unwrap_or(self.config.negative_ttl);
+            let ttl = Duration::from_secs(u64::from(ttl.min(self.config.max_ttl)));
+            debug!("{} does not resolve, caching for {:?}", hostname, ttl);
+
+            self.insert(
+                &hostname,
+                Entry::Negative {
+                    expires: Instant::now() + ttl,
+                },
+            );
+            return Err(NotFound(hostname).into());
+        }
+
+        let ttl = Duration::from_secs(u64::from(ttl.unwrap_or(0).min(self.config.max_ttl)));
+        debug!(
+            "Resolved {} to {:?}, caching for {:?}",
+            hostname, addrs, ttl
+        );
+
+        self.insert(
+            &hostname,
+            Entry::Positive {
+                addrs: addrs.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const TTL: u32 = 1;
+
+    /// Answers query for `positive.*` with an A record, `negative.*` with
+    /// NXDOMAIN and SOA record, and anything else with SERVFAIL.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let question = &query[12..];
+        let label = &question[1..1 + usize::from(question[0])];
+
+        let (rcode, ancount, nscount) = match label {
+            b"positive" => (dns::RCODE_NOERROR, 1, 0),
+            b"negative" => (dns::RCODE_NXDOMAIN, 0, 1),
+            _ => (2, 0, 0),
+        };
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&(0x8180 | u16::from(rcode)).to_be_bytes());
+
+        for count in &[1u16, ancount, nscount, 0] {
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+
+        buf.extend_from_slice(question);
+
+        if ancount > 0 {
+            buf.extend_from_slice(&[0xc0, 0x0c]);
+            buf.extend_from_slice(&dns::TYPE_A.to_be_bytes());
+            buf.extend_from_slice(&1u16.to_be_bytes());
+            buf.extend_from_slice(&TTL.to_be_bytes());
+            buf.extend_from_slice(&4u16.to_be_bytes());
+            buf.extend_from_slice(&[127, 0, 0, 1]);
+        }
+
+        if nscount > 0 {
+            buf.extend_from_slice(&[0xc0, 0x0c]);
+            buf.extend_from_slice(&dns::TYPE_SOA.to_be_bytes());
+            buf.extend_from_slice(&1u16.to_be_bytes());
+            buf.extend_from_slice(&3600u32.to_be_bytes());
+            buf.extend_from_slice(&22u16.to_be_bytes());
+            // Root MNAME and RNAME, then serial, refresh, retry and expire:
+            buf.extend_from_slice(&[0; 18]);
+            buf.extend_from_slice(&TTL.to_be_bytes());
+        }
+
+        buf
+    }
+
+    /// Starts stub DoH server, returning resolver that uses it and number of
+    /// queries server has received.
+    fn stub() -> (Resolver, Arc<AtomicUsize>) {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let counter = counter.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+
+                    let query = request.uri().query().unwrap().trim_start_matches("dns=");
+                    let query = base64::decode_config(query, base64::URL_SAFE_NO_PAD).unwrap();
+
+                    let response = Response::builder()
+                        .header(CONTENT_TYPE, DNS_MESSAGE)
+                        .body(Body::from(answer(&query)))
+                        .unwrap();
+
+                    future::ok::<_, Infallible>(response)
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/dns-query", server.local_addr());
+        tokio::spawn(server);
+
+        let config = ResolverConfig {
+            negative_ttl: 300,
+            ..Default::default()
+        };
+
+        (Resolver::new(url, config), queries)
+    }
+
+    fn expire() -> time::Delay {
+        time::delay_for(Duration::from_secs(u64::from(TTL)) + Duration::from_millis(100))
+    }
+
+    #[tokio::test]
+    async fn caches_positive_answer_for_its_ttl() {
+        let (resolver, queries) = stub();
+        let addrs = vec![IpAddr::from([127, 0, 0, 1])];
+
+        assert_eq!(resolver.resolve("positive.test").await.unwrap(), addrs);
+        assert_eq!(resolver.resolve("Positive.test").await.unwrap(), addrs);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        expire().await;
+
+        assert_eq!(resolver.resolve("positive.test").await.unwrap(), addrs);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_negative_answer_for_soa_minimum() {
+        let (resolver, queries) = stub();
+
+        for _ in 0..2 {
+            let e = resolver.resolve("negative.test").await.unwrap_err();
+            assert!(e.downcast_ref::<NotFound>().is_some(), "{}", e);
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // Default negative TTL is much longer, so this only expires if SOA
+        // minimum is used:
+        expire().await;
+
+        assert!(resolver.resolve("negative.test").await.is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_server_failure() {
+        let (resolver, queries) = stub();
+
+        for _ in 0..2 {
+            let e = resolver.resolve("failure.test").await.unwrap_err();
+            assert!(e.downcast_ref::<NotFound>().is_none(), "{}", e);
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,325 @@
+use failure::*;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::proxy_protocol;
+use crate::reload::Load;
+
+/// Static route that pins hostname (or `*.` wildcard pattern) to an upstream
+/// address, bypassing DNS, or to a different port on the resolved upstream.
+/// Route with `alpn` set only matches clients that offer that protocol.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub hostname: String,
+    #[serde(default)]
+    pub alpn: Option<String>,
+    #[serde(default)]
+    pub upstream: Option<SocketAddr>,
+    #[serde(default)]
+    pub upstream_port: Option<u16>,
+    /// Send PROXY protocol header to upstream before forwarding ClientHello
+    #[serde(default)]
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+impl Route {
+    fn matches(&self, alpn: &[&[u8]]) -> bool {
+        match &self.alpn {
+            Some(protocol) => alpn.contains(&protocol.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RouteFile {
+    routes: Vec<Route>,
+}
+
+/// Exact hostnames take precedence over wildcards, and longer wildcards take
+/// precedence over shorter ones. `*.example.net` matches any name under
+/// `example.net`, at any depth, but not `example.net` itself. For the same
+/// hostname, routes that match ALPN take precedence over ones without it.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    exact: HashMap<String, Vec<Route>>,
+    wildcards: Vec<(String, Vec<Route>)>,
+}
+
+impl RouteTable {
+    fn from_routes(routes: Vec<Route>) -> Fallible<Self> {
+        let mut table = RouteTable::default();
+
+        for route in routes {
+            if route.upstream.is_some() == route.upstream_port.is_some() {
+                bail!(
+                    "Route for {} needs either upstream or upstream_port",
+                    route.hostname
+                );
+            }
+
+            let hostname = route.hostname.to_ascii_lowercase();
+
+            let routes = if hostname.starts_with("*.") {
+                let suffix = &hostname[1..];
+
+                match table.wildcards.iter().position(|(s, _)| s == suffix) {
+                    Some(i) => &mut table.wildcards[i].1,
+                    None => {
+                        table.wildcards.push((suffix.to_string(), Vec::new()));
+                        &mut table.wildcards.last_mut().unwrap().1
+                    }
+                }
+            } else if hostname.contains('*') {
+                bail!(
+                    "Wildcard is only allowed as leftmost label: {}",
+                    route.hostname
+                );
+            } else {
+                table.exact.entry(hostname).or_default()
+            };
+
+            if routes.iter().any(|r| r.alpn == route.alpn) {
+                bail!("Duplicate route for {}", route.hostname);
+            }
+
+            routes.push(route);
+        }
+
+        for routes in table
+            .exact
+            .values_mut()
+            .chain(table.wildcards.iter_mut().map(|(_, routes)| routes))
+        {
+            routes.sort_by_key(|route| route.alpn.is_none());
+        }
+
+        table
+            .wildcards
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+
+        Ok(table)
+    }
+
+    /// Looks up route by hostname and protocols from ClientHello ALPN
+    /// extension. Hostname patterns whose routes all need protocols client
+    /// doesn't offer are skipped.
+    pub fn lookup(&self, hostname: &str, alpn: &[&[u8]]) -> Option<&Route> {
+        let hostname = hostname.to_ascii_lowercase();
+
+        let wildcards = self
+            .wildcards
+            .iter()
+            .filter(|(suffix, _)| {
+                hostname.len() > suffix.len() && hostname.ends_with(suffix.as_str())
+            })
+            .map(|(_, routes)| routes);
+
+        self.exact
+            .get(&hostname)
+            .into_iter()
+            .chain(wildcards)
+            .flat_map(|routes| routes.iter())
+            .find(|route| route.matches(alpn))
+    }
+}
+
+impl Load for RouteTable {
+    fn load(path: &Path) -> Fallible<Self> {
+        let file: RouteFile = toml::from_str(&fs::read_to_string(path)?)?;
+        Self::from_routes(file.routes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &str) -> Fallible<RouteTable> {
+        let file: RouteFile = toml::from_str(routes)?;
+        RouteTable::from_routes(file.routes)
+    }
+
+    fn upstream(table: &RouteTable, hostname: &str, alpn: &[&[u8]]) -> Option<String> {
+        table
+            .lookup(hostname, alpn)
+            .map(|route| route.upstream.unwrap().to_string())
+    }
+
+    #[test]
+    fn prefers_exact_hostname_over_wildcard() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "*.holohost.net"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "a.holohost.net"
+            upstream = "10.0.0.2:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            upstream(&table, "a.holohost.net", &[]).as_deref(),
+            Some("10.0.0.2:443")
+        );
+        assert_eq!(
+            upstream(&table, "b.holohost.net", &[]).as_deref(),
+            Some("10.0.0.1:443")
+        );
+    }
+
+    #[test]
+    fn prefers_deepest_wildcard() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "*.holohost.net"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "*.test.holohost.net"
+            upstream = "10.0.0.2:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            upstream(&table, "a.b.test.holohost.net", &[]).as_deref(),
+            Some("10.0.0.2:443")
+        );
+        assert_eq!(
+            upstream(&table, "a.b.holohost.net", &[]).as_deref(),
+            Some("10.0.0.1:443")
+        );
+    }
+
+    #[test]
+    fn prefers_route_for_offered_alpn() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "*.holohost.net"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "*.holohost.net"
+            alpn = "acme-tls/1"
+            upstream = "10.0.0.2:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            upstream(&table, "a.holohost.net", &[b"h2", b"acme-tls/1"]).as_deref(),
+            Some("10.0.0.2:443")
+        );
+        assert_eq!(
+            upstream(&table, "a.holohost.net", &[b"h2"]).as_deref(),
+            Some("10.0.0.1:443")
+        );
+    }
+
+    #[test]
+    fn skips_hostname_whose_routes_need_other_alpn() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "a.holohost.net"
+            alpn = "acme-tls/1"
+            upstream = "10.0.0.2:443"
+
+            [[routes]]
+            hostname = "*.holohost.net"
+            upstream = "10.0.0.1:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            upstream(&table, "a.holohost.net", &[b"h2"]).as_deref(),
+            Some("10.0.0.1:443")
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_routes() {
+        let e = table(
+            r#"
+            [[routes]]
+            hostname = "a.holohost.net"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "A.holohost.net"
+            upstream = "10.0.0.2:443"
+            "#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("Duplicate"), "{}", e);
+
+        let e = table(
+            r#"
+            [[routes]]
+            hostname = "*.holohost.net"
+            alpn = "acme-tls/1"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "*.holohost.net"
+            alpn = "acme-tls/1"
+            upstream_port = 8443
+            "#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("Duplicate"), "{}", e);
+    }
+
+    #[test]
+    fn wildcard_does_not_match_apex() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "*.holohost.net"
+            upstream = "10.0.0.1:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(upstream(&table, "holohost.net", &[]), None);
+        assert_eq!(upstream(&table, "aholohost.net", &[]), None);
+    }
+
+    #[test]
+    fn matches_case_insensitively() {
+        let table = table(
+            r#"
+            [[routes]]
+            hostname = "*.HoloHost.net"
+            upstream = "10.0.0.1:443"
+
+            [[routes]]
+            hostname = "Registry.Holo.Host"
+            upstream = "10.0.0.2:443"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            upstream(&table, "A.HOLOHOST.NET", &[]).as_deref(),
+            Some("10.0.0.1:443")
+        );
+        assert_eq!(
+            upstream(&table, "registry.holo.host", &[]).as_deref(),
+            Some("10.0.0.2:443")
+        );
+    }
+}
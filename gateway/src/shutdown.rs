@@ -0,0 +1,116 @@
+use failure::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Resolves with signal name once SIGTERM or SIGINT is received.
+pub async fn signalled() -> Fallible<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let name = tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+
+    Ok(name)
+}
+
+/// Tracks in-flight connections, so that they can be drained on shutdown
+/// and force-closed once grace period is over.
+pub struct Connections {
+    active: AtomicUsize,
+    draining: AtomicBool,
+    force_close_tx: watch::Sender<bool>,
+    force_close_rx: watch::Receiver<bool>,
+}
+
+pub struct ConnectionGuard<'a> {
+    active: &'a AtomicUsize,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Number of connections that closed on their own during drain, and number
+/// of connections that had to be force-closed.
+#[derive(Debug)]
+pub struct Summary {
+    pub drained: usize,
+    pub force_closed: usize,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        let (force_close_tx, force_close_rx) = watch::channel(false);
+
+        Connections {
+            active: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            force_close_tx,
+            force_close_rx,
+        }
+    }
+
+    pub fn track(&self) -> ConnectionGuard<'_> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            active: &self.active,
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Whether drain has started, for listeners that can't just be closed.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once connections are told to force-close.
+    pub async fn force_closed(&self) {
+        let mut force_close = self.force_close_rx.clone();
+
+        while let Some(value) = force_close.recv().await {
+            if value {
+                return;
+            }
+        }
+    }
+
+    async fn wait_idle(&self, deadline: Instant) {
+        while self.active() > 0 && Instant::now() < deadline {
+            time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Waits for active connections to close for up to `grace_period`, then
+    /// force-closes the remaining ones. Listeners have to be closed by then.
+    pub async fn drain(&self, grace_period: Duration) -> Summary {
+        self.draining.store(true, Ordering::SeqCst);
+        let initial = self.active();
+
+        self.wait_idle(Instant::now() + grace_period).await;
+
+        let remaining = self.active();
+
+        if remaining > 0 {
+            let _ = self.force_close_tx.broadcast(true);
+            self.wait_idle(Instant::now() + FORCE_CLOSE_TIMEOUT).await;
+        }
+
+        Summary {
+            drained: initial.saturating_sub(remaining),
+            force_closed: remaining,
+        }
+    }
+}
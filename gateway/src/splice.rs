@@ -0,0 +1,265 @@
+use failure::*;
+use futures::future::{self, BoxFuture, Future, FutureExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+use tracing::*;
+
+use std::cmp;
+use std::io;
+use std::net::Shutdown;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::SpliceConfig;
+
+const BUFFER_SIZE: usize = 16384;
+
+/// Whether zero-copy fallback has been logged already. Splicer only fails to
+/// set up once file descriptors run out, which tends to hit many connections
+/// at once, so it's only worth a warning once.
+#[cfg(target_os = "linux")]
+static FALLBACK_LOGGED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Fail)]
+pub enum Timeout {
+    #[fail(display = "No traffic in either direction for {:?}", _0)]
+    Idle(Duration),
+    #[fail(display = "Connection lifetime exceeded {:?}", _0)]
+    Lifetime(Duration),
+    #[fail(display = "Shutdown grace period is over")]
+    Shutdown,
+}
+
+impl Timeout {
+    fn reason(&self) -> &'static str {
+        match self {
+            Timeout::Idle(_) => "idle_timeout",
+            Timeout::Lifetime(_) => "lifetime_timeout",
+            Timeout::Shutdown => "force_closed",
+        }
+    }
+}
+
+/// Checks that `splice(2)` works on this host. It can be missing, or blocked
+/// by seccomp, which would otherwise only show once data is moved.
+#[cfg(target_os = "linux")]
+pub fn check_zero_copy() -> io::Result<()> {
+    crate::zerocopy::probe()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_zero_copy() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "splice(2) is only available on Linux",
+    ))
+}
+
+/// Time of last transfer in either direction, in milliseconds since start.
+pub struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Copies from reader to writer until EOF, then shuts down writer so that
+/// FIN is propagated to the other side. Bytes are counted as they are
+/// written, so that the count is accurate even if copy fails midway.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    activity: &Activity,
+    total: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = writer.shutdown().await;
+                return Err(e);
+            }
+        };
+
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        activity.touch();
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Resolves once connection has been idle for `idle_timeout`, or has been
+/// open for `max_lifetime`, whichever comes first.
+async fn watchdog(activity: &Activity, idle_timeout: Duration, max_lifetime: Duration) -> Timeout {
+    let lifetime_deadline = activity.start + max_lifetime;
+
+    loop {
+        let idle_deadline = activity.last() + idle_timeout;
+        let now = Instant::now();
+
+        if now >= lifetime_deadline {
+            return Timeout::Lifetime(max_lifetime);
+        }
+
+        if now >= idle_deadline {
+            return Timeout::Idle(idle_timeout);
+        }
+
+        time::delay_until(cmp::min(idle_deadline, lifetime_deadline)).await;
+    }
+}
+
+/// Number of bytes transferred in each direction.
+#[derive(Debug, Default)]
+pub struct Transfer {
+    pub inbound_to_outbound: u64,
+    pub outbound_to_inbound: u64,
+}
+
+/// Number of bytes transferred in each direction so far, updated as data is
+/// written, so that it can be accounted while connection is still open.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub inbound_to_outbound: AtomicU64,
+    pub outbound_to_inbound: AtomicU64,
+}
+
+impl Counters {
+    pub fn load(&self) -> Transfer {
+        Transfer {
+            inbound_to_outbound: self.inbound_to_outbound.load(Ordering::Relaxed),
+            outbound_to_inbound: self.outbound_to_inbound.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Copies data in both directions until both sides are done, or until idle
+/// or lifetime timeout is hit, or `shutdown` resolves, in which case both
+/// sockets are shut down.
+///
+/// EOF in one direction is propagated with a write shutdown, while the other
+/// direction keeps draining. An error in one direction doesn't interrupt the
+/// other one either.
+///
+/// If `zero_copy` is enabled (which is only left so if `check_zero_copy`
+/// passes), data is moved with `splice(2)`, otherwise it is copied through
+/// userspace buffer. Bytes are counted into
+/// `counters` as they are transferred.
+pub async fn splice(
+    mut inbound: TcpStream,
+    mut outbound: TcpStream,
+    config: &SpliceConfig,
+    counters: &Counters,
+    shutdown: impl Future<Output = ()>,
+) -> Fallible<Transfer> {
+    let activity = Activity::new();
+
+    #[cfg(target_os = "linux")]
+    let splicer = if config.zero_copy {
+        match crate::zerocopy::Splicer::new(&inbound, &outbound) {
+            Ok(splicer) => Some(splicer),
+            Err(e) => {
+                if FALLBACK_LOGGED.swap(true, Ordering::Relaxed) {
+                    debug!("Falling back to userspace copy: {}", e);
+                } else {
+                    warn!("Falling back to userspace copy: {}", e);
+                }
+
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let timeout = {
+        let (mut ri, mut wi) = inbound.split();
+        let (mut ro, mut wo) = outbound.split();
+
+        #[cfg(target_os = "linux")]
+        let zero_copy = splicer.as_ref().map(|splicer| {
+            splicer
+                .transfer(
+                    &activity,
+                    &counters.inbound_to_outbound,
+                    &counters.outbound_to_inbound,
+                )
+                .boxed()
+        });
+
+        #[cfg(not(target_os = "linux"))]
+        let zero_copy = None;
+
+        let transfer: BoxFuture<'_, (io::Result<()>, io::Result<()>)> = match zero_copy {
+            Some(transfer) => transfer,
+            None => future::join(
+                copy(&mut ri, &mut wo, &activity, &counters.inbound_to_outbound),
+                copy(&mut ro, &mut wi, &activity, &counters.outbound_to_inbound),
+            )
+            .boxed(),
+        };
+
+        tokio::select! {
+            (a, b) = transfer => {
+                if let Err(e) = a {
+                    debug!("Inbound to outbound copy failed: {}", e);
+                }
+
+                if let Err(e) = b {
+                    debug!("Outbound to inbound copy failed: {}", e);
+                }
+
+                None
+            }
+            timeout = watchdog(&activity, config.idle_timeout(), config.max_lifetime()) => {
+                Some(timeout)
+            }
+            _ = shutdown => Some(Timeout::Shutdown),
+        }
+    };
+
+    if let Some(timeout) = timeout {
+        info!(reason = timeout.reason(), "Closing connection: {}", timeout);
+
+        // Sockets might already be half-closed by now, so errors are irrelevant:
+        let _ = inbound.shutdown(Shutdown::Both);
+        let _ = outbound.shutdown(Shutdown::Both);
+    }
+
+    let transfer = counters.load();
+
+    info!(
+        inbound_to_outbound = transfer.inbound_to_outbound,
+        outbound_to_inbound = transfer.outbound_to_inbound,
+        "Connection closed"
+    );
+
+    Ok(transfer)
+}
@@ -0,0 +1,205 @@
+use failure::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, Instant};
+use tracing::*;
+
+use std::cmp;
+use std::io;
+use std::net::Shutdown;
+
+use crate::peek::peek_exact;
+use crate::rejection::{Alert, Rejection};
+
+// See: https://tls.ulfheim.net
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_HEADER_LENGTH: usize = 4;
+const RECORD_HEADER_LENGTH: usize = 5;
+
+const ALERT_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+const ALERT_DRAIN_MAX_TIME: Duration = Duration::from_secs(1);
+const ALERT_DRAIN_MAX_LENGTH: usize = 65536;
+const ALERT_LEVEL_FATAL: u8 = 2;
+const CONTENT_TYPE_ALERT: u8 = 21;
+
+/// Returns full length of the first handshake message (including its header),
+/// or `None` if the header hasn't been received yet.
+fn handshake_message_length(handshake: &[u8]) -> Option<usize> {
+    if handshake.len() < HANDSHAKE_HEADER_LENGTH {
+        return None;
+    }
+
+    let length = (usize::from(handshake[1]) << 16)
+        | (usize::from(handshake[2]) << 8)
+        | usize::from(handshake[3]);
+
+    Some(HANDSHAKE_HEADER_LENGTH + length)
+}
+
+/// Peeks (without consuming) TLS records from the stream until the first
+/// handshake message is complete, and returns it for `ClientHello::parse`.
+/// Handshake fragments split across several records are reassembled.
+/// `max_length` caps the total number of peeked bytes, including record
+/// headers, and `deadline` caps the time spent waiting for them.
+pub async fn read_client_hello(
+    stream: &mut TcpStream,
+    max_length: usize,
+    deadline: Instant,
+) -> Fallible<Vec<u8>> {
+    let mut handshake = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let buf = peek_exact(stream, offset + RECORD_HEADER_LENGTH, deadline).await?;
+        let header = &buf[offset..];
+
+        let content_type = header[0];
+        debug!("Content type: {}", content_type);
+
+        if content_type != CONTENT_TYPE_HANDSHAKE {
+            return Err(Rejection::NotHandshake.into());
+        }
+
+        debug!("Protocol version: {:#04x}{:02x}", header[1], header[2]);
+
+        let fragment_size = usize::from(header[3]) << 8 | usize::from(header[4]);
+        debug!("Fragment size: {:?}", fragment_size);
+
+        if fragment_size == 0 {
+            return Err(Rejection::MalformedClientHello("handshake record is empty").into());
+        }
+
+        let record_end = offset + RECORD_HEADER_LENGTH + fragment_size;
+
+        if record_end > max_length {
+            return Err(Rejection::ClientHelloTooLong(max_length).into());
+        }
+
+        let buf = peek_exact(stream, record_end, deadline).await?;
+
+        handshake.extend_from_slice(&buf[offset + RECORD_HEADER_LENGTH..]);
+        offset = record_end;
+
+        if let Some(length) = handshake_message_length(&handshake) {
+            if handshake.len() >= length {
+                handshake.truncate(length);
+                return Ok(handshake);
+            }
+        }
+    }
+}
+
+/// Writes fatal alert record and closes write half of the stream.
+///
+/// ClientHello has only been peeked so far, and closing a socket with unread
+/// data makes the kernel send RST, which can make client discard the alert.
+/// So whatever has been received is drained first, until client is quiet
+/// for a while. Handshake deadline doesn't apply anymore, so draining is
+/// capped separately, in case client keeps trickling data.
+pub async fn send_alert(stream: &mut TcpStream, alert: Alert) -> io::Result<()> {
+    let mut buf = vec![0; 4096];
+    let deadline = Instant::now() + ALERT_DRAIN_MAX_TIME;
+    let mut drained = 0;
+
+    while drained < ALERT_DRAIN_MAX_LENGTH {
+        let idle_deadline = cmp::min(Instant::now() + ALERT_DRAIN_TIMEOUT, deadline);
+
+        match time::timeout_at(idle_deadline, stream.read(&mut buf)).await {
+            Ok(result) => match result? {
+                0 => break,
+                n => drained += n,
+            },
+            Err(_) => break,
+        }
+    }
+
+    let record = [
+        CONTENT_TYPE_ALERT,
+        0x03,
+        0x03,
+        0x00,
+        0x02,
+        ALERT_LEVEL_FATAL,
+        alert as u8,
+    ];
+
+    stream.write_all(&record).await?;
+    stream.shutdown(Shutdown::Write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peek::tests::{chunks, loopback};
+    use crate::peek::Timeout;
+
+    fn handshake() -> Vec<u8> {
+        let mut handshake = vec![1, 0, 0, 200];
+        handshake.extend((0..200).map(|x| x as u8));
+        handshake
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let length = fragment.len() as u16;
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&length.to_be_bytes());
+        record.extend_from_slice(fragment);
+        record
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    #[tokio::test]
+    async fn reassembles_records_sent_in_chunks() {
+        let handshake = handshake();
+        let (first, second) = handshake.split_at(100);
+
+        let mut data = record(first);
+        data.extend(record(second));
+
+        let mut stream = loopback(chunks(&data, 7), Duration::from_millis(5), false).await;
+
+        assert_eq!(
+            read_client_hello(&mut stream, 1024, deadline())
+                .await
+                .unwrap(),
+            handshake
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_if_peer_closes() {
+        let mut stream = loopback(Vec::new(), Duration::from_millis(10), true).await;
+
+        let e = read_client_hello(&mut stream, 1024, deadline())
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<Timeout>().is_none(), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn times_out_on_truncated_record() {
+        let data = record(&handshake());
+        let mut stream = loopback(chunks(&data[..50], 7), Duration::from_millis(5), false).await;
+        let deadline = Instant::now() + Duration::from_millis(300);
+
+        let e = read_client_hello(&mut stream, 1024, deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<Timeout>().unwrap().received, 50);
+    }
+
+    #[tokio::test]
+    async fn caps_alert_drain_for_trickling_client() {
+        let data = vec![0; 100];
+        let mut stream = loopback(chunks(&data, 1), Duration::from_millis(50), false).await;
+        let started = Instant::now();
+
+        send_alert(&mut stream, Alert::HandshakeFailure)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < ALERT_DRAIN_MAX_TIME + ALERT_DRAIN_TIMEOUT);
+    }
+}
@@ -0,0 +1,403 @@
+//! Relay of QUIC datagram flows to upstream hosts. Each flow gets its own
+//! upstream socket, and is found by connection ID for datagrams from client,
+//! since client address may change mid-connection. Replies go to client's
+//! original address until it is seen to move, see `Peer`.
+
+use failure::*;
+use futures::future::Future;
+use tokio::net::udp::SendHalf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::time;
+use tracing::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::QuicConfig;
+use crate::quic;
+use crate::splice::{Counters, Transfer};
+
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+const QUEUE_SIZE: usize = 64;
+/// Max number of connection IDs that upstream can choose for a single flow,
+/// so that it can't grow the table without bound
+const MAX_CIDS_PER_FLOW: usize = 8;
+/// Min time between changes of client address of a flow, and from its start
+/// until the first one
+const MIN_MIGRATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Datagram from client, along with address it came from.
+pub type Datagram = (SocketAddr, Vec<u8>);
+
+#[derive(Clone)]
+struct Entry {
+    id: u64,
+    queue: mpsc::Sender<Datagram>,
+}
+
+#[derive(Default)]
+struct Table {
+    by_cid: HashMap<Vec<u8>, Entry>,
+    by_peer: HashMap<SocketAddr, Entry>,
+    /// Connection IDs chosen by upstream, by flow id
+    upstream_cids: HashMap<u64, Vec<Vec<u8>>>,
+    /// Lengths of connection IDs chosen by upstreams, which short header
+    /// packets don't carry
+    cid_lengths: BTreeSet<usize>,
+}
+
+/// Table of active flows, by connection ID and by client address.
+pub struct Flows {
+    config: QuicConfig,
+    table: Mutex<Table>,
+    next_id: AtomicU64,
+}
+
+impl Flows {
+    pub fn new(config: QuicConfig) -> Self {
+        Flows {
+            config,
+            table: Mutex::new(Table::default()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns queue of existing flow that datagram belongs to. Short header
+    /// packets with unknown connection ID fall back to client address, long
+    /// header ones are new connections.
+    pub fn lookup(&self, datagram: &[u8], peer: SocketAddr) -> Option<mpsc::Sender<Datagram>> {
+        let table = self.table.lock().unwrap();
+
+        let entry = if quic::is_long_header(datagram) {
+            quic::destination_cid(datagram, 0).and_then(|cid| table.by_cid.get(cid))
+        } else {
+            table
+                .cid_lengths
+                .iter()
+                .filter_map(|&length| quic::destination_cid(datagram, length))
+                .find_map(|cid| table.by_cid.get(cid))
+                .or_else(|| table.by_peer.get(&peer))
+        };
+
+        entry.map(|entry| entry.queue.clone())
+    }
+
+    /// Registers new flow by client's original destination connection ID.
+    /// Returns `None` if there are too many flows already.
+    pub fn open(self: &Arc<Self>, peer: SocketAddr, dcid: &[u8]) -> Option<Flow> {
+        let mut table = self.table.lock().unwrap();
+
+        if table.by_peer.len() >= self.config.max_flows {
+            return None;
+        }
+
+        let (sender, queue) = mpsc::channel(QUEUE_SIZE);
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            queue: sender,
+        };
+
+        table.by_cid.insert(dcid.to_vec(), entry.clone());
+        table.by_peer.insert(peer, entry.clone());
+
+        Some(Flow {
+            flows: self.clone(),
+            entry,
+            peer,
+            queue,
+        })
+    }
+
+    fn register(&self, cid: &[u8], entry: &Entry) {
+        let mut table = self.table.lock().unwrap();
+
+        if table.by_cid.contains_key(cid) {
+            return;
+        }
+
+        let cids = table.upstream_cids.entry(entry.id).or_default();
+
+        if cids.len() >= MAX_CIDS_PER_FLOW {
+            debug!("Too many connection IDs, ignoring {:02x?}", cid);
+            return;
+        }
+
+        cids.push(cid.to_vec());
+        table.by_cid.insert(cid.to_vec(), entry.clone());
+        table.cid_lengths.insert(cid.len());
+    }
+
+    fn remove(&self, id: u64) {
+        let mut table = self.table.lock().unwrap();
+
+        table.by_cid.retain(|_, entry| entry.id != id);
+        table.by_peer.retain(|_, entry| entry.id != id);
+
+        if table.upstream_cids.remove(&id).is_some() {
+            table.cid_lengths = table
+                .upstream_cids
+                .values()
+                .flatten()
+                .map(Vec::len)
+                .collect();
+        }
+    }
+}
+
+/// Client address that replies go to. Source addresses of datagrams can be
+/// spoofed, so a new one only becomes a candidate, which replaces current
+/// address once upstream replies, unless client sends from current address
+/// again meanwhile. Long header packets are never taken as migration, and
+/// address changes are rate-limited.
+struct Peer {
+    current: SocketAddr,
+    candidate: Option<SocketAddr>,
+    changed: Instant,
+}
+
+impl Peer {
+    fn new(addr: SocketAddr, now: Instant) -> Self {
+        Peer {
+            current: addr,
+            candidate: None,
+            changed: now,
+        }
+    }
+
+    /// Notes address that datagram forwarded to upstream came from.
+    fn received(&mut self, addr: SocketAddr, datagram: &[u8]) {
+        if addr == self.current {
+            self.candidate = None;
+        } else if !quic::is_long_header(datagram) {
+            self.candidate = Some(addr);
+        }
+    }
+
+    /// Returns address to send upstream reply to, which is candidate one if
+    /// there is one and it's been long enough since last change.
+    fn reply_to(&mut self, now: Instant) -> SocketAddr {
+        if let Some(candidate) = self.candidate {
+            if now.duration_since(self.changed) >= MIN_MIGRATION_INTERVAL {
+                debug!(from = %self.current, to = %candidate, "Client address changed");
+                self.current = candidate;
+                self.candidate = None;
+                self.changed = now;
+            }
+        }
+
+        self.current
+    }
+}
+
+/// Flow that is removed from the table when dropped.
+pub struct Flow {
+    flows: Arc<Flows>,
+    entry: Entry,
+    peer: SocketAddr,
+    queue: mpsc::Receiver<Datagram>,
+}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        self.flows.remove(self.entry.id);
+    }
+}
+
+impl Flow {
+    /// Forwards datagrams buffered so far and then queued ones to upstream,
+    /// and replies back to client through listener socket, until flow is
+    /// idle for longer than idle timeout. Connection IDs that upstream
+    /// chooses are learned from its long header packets. Bytes are counted
+    /// into `counters` as they are relayed. Flow ends early once `shutdown`
+    /// resolves.
+    pub async fn relay(
+        mut self,
+        upstream: SocketAddr,
+        datagrams: Vec<Vec<u8>>,
+        replies: Arc<AsyncMutex<SendHalf>>,
+        counters: &Counters,
+        shutdown: impl Future<Output = ()>,
+    ) -> Fallible<Transfer> {
+        tokio::pin!(shutdown);
+
+        let local: SocketAddr = match upstream {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(upstream).await?;
+
+        let (mut upstream_rx, mut upstream_tx) = socket.split();
+        let mut peer = Peer::new(self.peer, Instant::now());
+
+        for datagram in datagrams {
+            upstream_tx.send(&datagram).await?;
+            counters
+                .inbound_to_outbound
+                .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+        }
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+        loop {
+            tokio::select! {
+                datagram = self.queue.recv() => {
+                    let (addr, datagram) = match datagram {
+                        Some(datagram) => datagram,
+                        None => break,
+                    };
+
+                    upstream_tx.send(&datagram).await?;
+                    peer.received(addr, &datagram);
+                    counters
+                        .inbound_to_outbound
+                        .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                }
+                result = upstream_rx.recv(&mut buf) => {
+                    let datagram = &buf[..result?];
+
+                    if let Some(cid) = quic::source_cid(datagram) {
+                        self.flows.register(cid, &self.entry);
+                    }
+
+                    let peer = peer.reply_to(Instant::now());
+                    replies.lock().await.send_to(datagram, &peer).await?;
+                    counters
+                        .outbound_to_inbound
+                        .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                }
+                _ = time::delay_for(self.flows.config.idle_timeout()) => {
+                    debug!("Flow is idle, expiring");
+                    break;
+                }
+                _ = &mut shutdown => {
+                    info!(reason = "force_closed", "Closing flow: Shutdown grace period is over");
+                    break;
+                }
+            }
+        }
+
+        Ok(counters.load())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_header(cid: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0x40];
+        datagram.extend_from_slice(cid);
+        datagram.extend_from_slice(&[0; 20]);
+        datagram
+    }
+
+    fn long_header(dcid: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        datagram.extend_from_slice(dcid);
+        datagram.extend_from_slice(&[0; 20]);
+        datagram
+    }
+
+    #[test]
+    fn caps_upstream_cids_per_flow() {
+        let flows = Arc::new(Flows::new(QuicConfig::default()));
+        let client: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:1234".parse().unwrap();
+
+        let flow = flows.open(client, &[0xff; 8]).unwrap();
+
+        for i in 0..MAX_CIDS_PER_FLOW as u8 + 2 {
+            flows.register(&[i; 4], &flow.entry);
+        }
+
+        let last = MAX_CIDS_PER_FLOW as u8 - 1;
+        assert!(flows.lookup(&short_header(&[last; 4]), other).is_some());
+        assert!(flows.lookup(&short_header(&[last + 1; 4]), other).is_none());
+        assert_eq!(
+            flows.table.lock().unwrap().by_cid.len(),
+            MAX_CIDS_PER_FLOW + 1
+        );
+    }
+
+    #[test]
+    fn forgets_cid_lengths_of_removed_flows() {
+        let flows = Arc::new(Flows::new(QuicConfig::default()));
+        let first = flows
+            .open("192.0.2.1:1234".parse().unwrap(), &[1; 8])
+            .unwrap();
+        let second = flows
+            .open("192.0.2.2:1234".parse().unwrap(), &[2; 8])
+            .unwrap();
+
+        flows.register(&[1; 4], &first.entry);
+        flows.register(&[2; 6], &second.entry);
+        drop(first);
+
+        let table = flows.table.lock().unwrap();
+        assert_eq!(table.cid_lengths.iter().copied().collect::<Vec<_>>(), [6]);
+        assert_eq!(table.by_cid.len(), 2);
+    }
+
+    #[test]
+    fn spoofed_datagrams_do_not_move_peer() {
+        let flows = Arc::new(Flows::new(QuicConfig::default()));
+        let client: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let spoofer: SocketAddr = "198.51.100.1:1234".parse().unwrap();
+
+        let flow = flows.open(client, &[0xff; 8]).unwrap();
+        flows.register(&[1; 4], &flow.entry);
+
+        let start = Instant::now();
+        let later = start + MIN_MIGRATION_INTERVAL;
+
+        // Long header packets never move the flow, even with known
+        // connection ID:
+        let datagram = long_header(&[0xff; 8]);
+        assert!(flows.lookup(&datagram, spoofer).is_some());
+
+        let mut peer = Peer::new(client, start);
+        peer.received(spoofer, &datagram);
+        assert_eq!(peer.reply_to(later), client);
+
+        // Neither do short header ones, if client keeps sending from its
+        // address before upstream replies:
+        let datagram = short_header(&[1; 4]);
+        assert!(flows.lookup(&datagram, spoofer).is_some());
+
+        peer.received(spoofer, &datagram);
+        peer.received(client, &datagram);
+        assert_eq!(peer.reply_to(later), client);
+
+        // Or if flow has only just started:
+        let mut peer = Peer::new(client, start);
+        peer.received(spoofer, &datagram);
+        assert_eq!(peer.reply_to(start), client);
+    }
+
+    #[test]
+    fn follows_client_once_upstream_replies() {
+        let client: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let rebound: SocketAddr = "192.0.2.1:5678".parse().unwrap();
+        let migrated: SocketAddr = "203.0.113.1:1234".parse().unwrap();
+        let datagram = short_header(&[1; 4]);
+
+        let start = Instant::now();
+        let later = start + MIN_MIGRATION_INTERVAL;
+        let mut peer = Peer::new(client, start);
+
+        peer.received(rebound, &datagram);
+        assert_eq!(peer.current, client);
+        assert_eq!(peer.reply_to(later), rebound);
+
+        // Next change is rate-limited:
+        peer.received(migrated, &datagram);
+        assert_eq!(peer.reply_to(later + MIN_MIGRATION_INTERVAL / 2), rebound);
+        assert_eq!(peer.reply_to(later + MIN_MIGRATION_INTERVAL), migrated);
+    }
+}
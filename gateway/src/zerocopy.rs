@@ -0,0 +1,327 @@
+//! Linux-only fast path for splice: data is moved socket -> pipe -> socket
+//! with `splice(2)`, so that it never has to be copied to userspace.
+
+use futures::future::{self, poll_fn};
+use mio::unix::EventedFd;
+use mio::{Evented, Poll as MioPoll, PollOpt, Ready, Token};
+use tokio::io::PollEvented;
+use tokio::net::TcpStream;
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use crate::splice::Activity;
+
+/// Default pipe capacity on Linux, every splice moves at most this much.
+const PIPE_SIZE: usize = 65536;
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Socket fd duplicated from a `TcpStream`, registered with the reactor on
+/// its own so that readiness can be polled and cleared around `splice(2)`.
+struct Fd(RawFd);
+
+impl Fd {
+    fn dup(stream: &TcpStream) -> io::Result<Self> {
+        let fd = cvt(unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) })?;
+        Ok(Fd(fd))
+    }
+}
+
+impl Evented for Fd {
+    fn register(
+        &self,
+        poll: &MioPoll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &MioPoll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &MioPoll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) })?;
+
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let n = unsafe { libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len, flags) };
+
+    if n == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Moves a byte between two pipes, to check that splice(2) is supported.
+pub fn probe() -> io::Result<()> {
+    let from = Pipe::new()?;
+    let to = Pipe::new()?;
+
+    let byte = [0u8];
+    if unsafe { libc::write(from.write, byte.as_ptr() as *const libc::c_void, 1) } != 1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match splice(from.read, to.write, 1)? {
+        1 => Ok(()),
+        n => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("splice(2) moved {} bytes instead of 1", n),
+        )),
+    }
+}
+
+struct Socket(PollEvented<Fd>);
+
+impl Socket {
+    fn new(stream: &TcpStream) -> io::Result<Self> {
+        Ok(Socket(PollEvented::new(Fd::dup(stream)?)?))
+    }
+
+    fn fd(&self) -> RawFd {
+        self.0.get_ref().0
+    }
+
+    /// Moves up to `PIPE_SIZE` bytes from socket into (empty) pipe.
+    fn poll_splice_to(&self, cx: &mut Context<'_>, pipe: &Pipe) -> Poll<io::Result<usize>> {
+        futures::ready!(self.0.poll_read_ready(cx, Ready::readable()))?;
+
+        match splice(self.fd(), pipe.write, PIPE_SIZE) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.0.clear_read_ready(cx, Ready::readable())?;
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    /// Moves up to `len` bytes from pipe into socket.
+    fn poll_splice_from(
+        &self,
+        cx: &mut Context<'_>,
+        pipe: &Pipe,
+        len: usize,
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.0.poll_write_ready(cx))?;
+
+        match splice(pipe.read, self.fd(), len) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.0.clear_write_ready(cx)?;
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        cvt(unsafe { libc::shutdown(self.fd(), libc::SHUT_WR) })?;
+        Ok(())
+    }
+}
+
+async fn copy(
+    src: &Socket,
+    dst: &Socket,
+    pipe: &Pipe,
+    activity: &Activity,
+    total: &AtomicU64,
+) -> io::Result<()> {
+    loop {
+        let n = match poll_fn(|cx| src.poll_splice_to(cx, pipe)).await {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = dst.shutdown_write();
+                return Err(e);
+            }
+        };
+
+        if n == 0 {
+            return dst.shutdown_write();
+        }
+
+        let mut left = n;
+
+        while left > 0 {
+            left -= poll_fn(|cx| dst.poll_splice_from(cx, pipe, left)).await?;
+        }
+
+        activity.touch();
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Sockets and pipes for both directions, set up before any data is moved,
+/// so that any failure here leaves streams intact for the fallback copy loop.
+pub struct Splicer {
+    inbound: Socket,
+    outbound: Socket,
+    inbound_to_outbound: Pipe,
+    outbound_to_inbound: Pipe,
+}
+
+impl Splicer {
+    pub fn new(inbound: &TcpStream, outbound: &TcpStream) -> io::Result<Self> {
+        Ok(Splicer {
+            inbound: Socket::new(inbound)?,
+            outbound: Socket::new(outbound)?,
+            inbound_to_outbound: Pipe::new()?,
+            outbound_to_inbound: Pipe::new()?,
+        })
+    }
+
+    pub async fn transfer(
+        &self,
+        activity: &Activity,
+        inbound_to_outbound: &AtomicU64,
+        outbound_to_inbound: &AtomicU64,
+    ) -> (io::Result<()>, io::Result<()>) {
+        future::join(
+            copy(
+                &self.inbound,
+                &self.outbound,
+                &self.inbound_to_outbound,
+                activity,
+                inbound_to_outbound,
+            ),
+            copy(
+                &self.outbound,
+                &self.inbound,
+                &self.outbound_to_inbound,
+                activity,
+                outbound_to_inbound,
+            ),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use std::net::Shutdown;
+
+    /// Returns both ends of a loopback connection.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        (client, listener.accept().await.unwrap().0)
+    }
+
+    #[test]
+    fn probes_splice() {
+        probe().unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfers_both_ways_until_eof() {
+        let (mut client, inbound) = pair().await;
+        let (outbound, mut upstream) = pair().await;
+
+        let splicer = Splicer::new(&inbound, &outbound).unwrap();
+        let activity = Activity::new();
+        let inbound_to_outbound = AtomicU64::new(0);
+        let outbound_to_inbound = AtomicU64::new(0);
+
+        // Request spans several pipe-fulls:
+        let request: Vec<u8> = (0..3 * PIPE_SIZE + 1).map(|i| i as u8).collect();
+        let response = vec![0x5a; 1000];
+
+        let client_side = async {
+            client.write_all(&request).await.unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+
+        let upstream_side = async {
+            let mut buf = Vec::new();
+            upstream.read_to_end(&mut buf).await.unwrap();
+
+            upstream.write_all(&response).await.unwrap();
+            upstream.shutdown(Shutdown::Write).unwrap();
+            buf
+        };
+
+        let ((a, b), received_response, received_request) = future::join3(
+            splicer.transfer(&activity, &inbound_to_outbound, &outbound_to_inbound),
+            client_side,
+            upstream_side,
+        )
+        .await;
+
+        a.unwrap();
+        b.unwrap();
+        assert!(received_request == request);
+        assert_eq!(received_response, response);
+        assert_eq!(
+            inbound_to_outbound.load(Ordering::Relaxed),
+            request.len() as u64
+        );
+        assert_eq!(
+            outbound_to_inbound.load(Ordering::Relaxed),
+            response.len() as u64
+        );
+    }
+}